@@ -1,18 +1,101 @@
 extern crate bit_set;
-extern crate lzma_rs;
+extern crate lzma_rust2;
 extern crate byteorder;
-
-fn or_archive_error<R>(result: Result<R, io::Error>) -> Result<R, ArchiveError> {
-    result.map_err(|e| ArchiveError::new(&e.to_string()))
+extern crate crc32fast;
+extern crate miniz_oxide;
+extern crate deflate64;
+extern crate bzip2;
+extern crate ppmd_rust;
+extern crate aes;
+extern crate cbc;
+extern crate sha2;
+#[cfg(feature = "zstd")]
+extern crate ruzstd;
+#[cfg(feature = "lz4")]
+extern crate lz4_flex;
+#[cfg(feature = "brotli")]
+extern crate brotli_decompressor;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ErrorKind {
+    Other,
+    /// The 20-byte start header didn't match its CRC
+    StartHeaderCorrupt,
+    /// The next header was out of bounds, or didn't match its CRC
+    HeaderCorrupt,
+    /// An extracted file didn't match its CRC
+    FileCrcMismatch,
+    /// A decoded folder didn't match its CRC
+    FolderCrcMismatch,
+    /// A packed stream didn't match its CRC
+    PackCrcMismatch,
+    /// The archive is encrypted, and no password was given
+    PasswordRequired,
+    /// Decrypting with the given password produced data that couldn't be decoded or didn't match its CRC
+    WrongPassword,
+    /// A decoder would need more memory than `DecompressOptions.memory_limit` allows
+    MemoryLimitExceeded,
 }
 
 #[derive(Debug, Clone)]
 pub struct ArchiveError {
+    pub kind: ErrorKind,
     pub message: String
 }
 impl ArchiveError {
-    fn new(message: &str) -> ArchiveError {
-        ArchiveError { message: String::from(message) }
+    pub fn new(message: &str) -> ArchiveError {
+        ArchiveError::with_kind(ErrorKind::Other, message)
+    }
+
+    pub fn with_kind(kind: ErrorKind, message: &str) -> ArchiveError {
+        ArchiveError { kind, message: String::from(message) }
+    }
+}
+
+/// How CRC mismatches in the extracted data are handled. The archive headers are always checked.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum Verification {
+    /// Fail on the first CRC mismatch
+    #[default]
+    Strict,
+    /// Return the data anyway, but flag the files that didn't match their CRC
+    Lenient,
+    /// Don't check the CRCs at all
+    Off,
+}
+
+#[derive(Debug, Clone)]
+pub struct DecompressOptions {
+    pub verification: Verification,
+    /// If the start header is corrupt, try to locate the next header by scanning backwards
+    /// from the end of the file, instead of failing right away.
+    pub recover_start_header: bool,
+    /// Also check the packed (still compressed) streams against their CRCs before decoding them.
+    /// Most archivers don't store these, in which case there is nothing to check.
+    pub verify_pack_crcs: bool,
+    /// The password for encrypted (AES-256) archives
+    pub password: Option<String>,
+    /// The decoders, by method ID. Has all the built-in ones by default.
+    pub codecs: CodecRegistry,
+    /// The most memory (in bytes) a decoder may allocate for its dictionary or model,
+    /// checked before decoding. `None` means no limit.
+    pub memory_limit: Option<u64>,
+}
+
+// Enough for the dictionaries of 7-Zip's presets and then some, but not for a hostile archive
+// claiming a 4 GB dictionary
+const DEFAULT_MEMORY_LIMIT: u64 = 1 << 30;
+
+impl Default for DecompressOptions {
+    fn default() -> DecompressOptions {
+        DecompressOptions {
+            verification: Verification::default(),
+            recover_start_header: false,
+            verify_pack_crcs: false,
+            password: None,
+            codecs: CodecRegistry::default(),
+            memory_limit: Some(DEFAULT_MEMORY_LIMIT),
+        }
     }
 }
 
@@ -23,17 +106,25 @@ pub struct InternalArchive {
 
 const SIGNATURE_HEADER_SIZE: u64 = 32;
 const SIGNATURE: [u8; 6] = [0x37, 0x7A, 0xBC, 0xAF, 0x27, 0x1C];
+const START_HEADER_SIZE: usize = 20;
 
+use std::borrow::Cow;
 use std::io;
-use std::io::Read;
 mod read_utils;
 mod nid;
 mod header;
 mod decode;
+pub mod codec;
+pub mod methods;
+mod bcj;
+mod bcj2;
+mod crypto;
 mod encoded_header;
 
 use internal::nid::NID;
-use internal::header::Header;
+use internal::header::{Entry, Header};
+use internal::encoded_header::{Folder, InStream};
+use internal::codec::CodecRegistry;
 
 #[derive(Debug)]
 struct StartHeader {
@@ -43,9 +134,9 @@ struct StartHeader {
 }
 
 fn read_start_header<R>(buf: &mut R) -> Result<StartHeader, ArchiveError> where R: io::BufRead {
-    let next_header_offset = read_utils::read_uint64(buf);
-    let next_header_size  = read_utils::read_uint64(buf);
-    let next_header_crc = read_utils::read_uint32(buf);
+    let next_header_offset = read_utils::read_uint64(buf)?;
+    let next_header_size  = read_utils::read_uint64(buf)?;
+    let next_header_crc = read_utils::read_uint32(buf)?;
     Ok(StartHeader {
         next_header_offset,
         next_header_size,
@@ -53,7 +144,103 @@ fn read_start_header<R>(buf: &mut R) -> Result<StartHeader, ArchiveError> where
     })
 }
 
+// 7-Zip always writes the next header at the very end of the archive, so we look for the
+// last position where a header (starting with NID::Header or NID::EncodedHeader) running
+// to the end of the file matches the CRC recorded in the start header.
+fn recover_start_header(data: &[u8], damaged: &StartHeader) -> Result<StartHeader, ArchiveError> {
+    let mut suffix = crc32fast::Hasher::new();
+    let mut suffix_start = data.len();
+    for pos in (SIGNATURE_HEADER_SIZE as usize..data.len()).rev() {
+        // NID::Header or NID::EncodedHeader
+        if data[pos] != 0x01 && data[pos] != 0x17 {
+            continue;
+        }
+        let mut hasher = crc32fast::Hasher::new();
+        hasher.update(&data[pos..suffix_start]);
+        hasher.combine(&suffix);
+        suffix = hasher;
+        suffix_start = pos;
+
+        if suffix.clone().finalize() == damaged.next_header_crc {
+            return Ok(StartHeader {
+                next_header_offset: pos as u64 - SIGNATURE_HEADER_SIZE,
+                next_header_size: (data.len() - pos) as u64,
+                next_header_crc: damaged.next_header_crc
+            });
+        }
+    }
+    Err(ArchiveError::with_kind(ErrorKind::StartHeaderCorrupt, "Start header is corrupt, and no valid header could be found"))
+}
+
+fn get_next_header<'a>(data: &'a [u8], start_header: &StartHeader) -> Result<&'a [u8], ArchiveError> {
+    let start = SIGNATURE_HEADER_SIZE.checked_add(start_header.next_header_offset);
+    let end = start.and_then(|start| start.checked_add(start_header.next_header_size));
+    let next_header = match (start, end) {
+        (Some(start), Some(end)) if end <= data.len() as u64 => &data[start as usize..end as usize],
+        _ => return Err(ArchiveError::with_kind(ErrorKind::HeaderCorrupt, "Next header is out of bounds"))
+    };
+
+    if crc32fast::hash(next_header) != start_header.next_header_crc {
+        return Err(ArchiveError::with_kind(ErrorKind::HeaderCorrupt, "Next header CRC mismatch"));
+    }
+    Ok(next_header)
+}
+
 pub fn decompress(data: &[u8]) -> Result<InternalArchive, ArchiveError> {
+    decompress_with_options(data, &DecompressOptions::default())
+}
+
+pub fn decompress_with_options(data: &[u8], options: &DecompressOptions) -> Result<InternalArchive, ArchiveError> {
+    let header = read_archive_header(data, options)?;
+
+    let mut files: Vec<File> = Vec::new();
+    visit_entries(&header, data, options, |entry, contents| {
+        let contents = contents?;
+        files.push(File {
+            name: entry.name.to_string(),
+            data: contents.data.to_vec(),
+            crc_mismatch: contents.crc_mismatch,
+            method: header.streams_info.folders[entry.folder_index].describe(),
+        });
+        Ok(())
+    })?;
+
+    Ok(InternalArchive {
+        files
+    })
+}
+
+/// Decodes every folder and checks all the CRCs, like `7z t`, without keeping the extracted files around.
+/// The `verification` option is ignored; a CRC mismatch is always reported on the entry.
+///
+/// This is not a streaming check. Each folder is decoded into memory in full before its CRCs are
+/// checked, so the peak memory use is about the unpacked size of the largest folder. A solid archive
+/// is usually a single folder, so testing it needs about as much memory as its unpacked size; it only
+/// saves the copy of every file that `decompress` returns.
+pub fn test_archive(data: &[u8], options: &DecompressOptions) -> Result<TestReport, ArchiveError> {
+    let options = DecompressOptions { verification: Verification::Lenient, ..options.clone() };
+    let header = read_archive_header(data, &options)?;
+
+    let mut entries: Vec<TestEntry> = Vec::new();
+    visit_entries(&header, data, &options, |entry, contents| {
+        let status = match contents {
+            Ok(ref contents) if contents.crc_mismatch => TestStatus::CrcMismatch,
+            Ok(_) => TestStatus::Ok,
+            Err(e) => TestStatus::DecodeError(e.message)
+        };
+        entries.push(TestEntry {
+            name: entry.name.to_string(),
+            status
+        });
+        Ok(())
+    })?;
+
+    Ok(TestReport {
+        entries
+    })
+}
+
+fn read_archive_header(data: &[u8], options: &DecompressOptions) -> Result<Header, ArchiveError> {
     let mut buf = io::Cursor::new(data);
 
     if data.len() < 12 {
@@ -71,95 +258,216 @@ pub fn decompress(data: &[u8]) -> Result<InternalArchive, ArchiveError> {
         return Err(ArchiveError::new(&format!("Unsupported 7z version ({},{})", major_version, minor_version)));
     }
 
+    if data.len() < SIGNATURE_HEADER_SIZE as usize {
+        return Err(ArchiveError::new("The file is too small"));
+    }
+
     buf.set_position(8);
 
-    let _start_header_crc = 0xFFFFFFFF & read_utils::read_uint32(&mut buf);
-    let start_header = read_start_header(&mut buf)?;
+    let start_header_crc = read_utils::read_uint32(&mut buf)?;
+    let mut start_header = read_start_header(&mut buf)?;
+
+    if crc32fast::hash(&data[12..12 + START_HEADER_SIZE]) != start_header_crc {
+        if !options.recover_start_header {
+            return Err(ArchiveError::with_kind(ErrorKind::StartHeaderCorrupt, "Start header CRC mismatch"));
+        }
+        start_header = recover_start_header(data, &start_header)?;
+    }
 
-    buf.set_position(SIGNATURE_HEADER_SIZE + start_header.next_header_offset);
+    let next_header = get_next_header(data, &start_header)?;
+    let mut next_header_buf = io::Cursor::new(next_header);
 
-    let mut nid = nid::read_nid(&mut buf)?;
+    let mut nid = nid::read_nid(&mut next_header_buf)?;
     if nid == NID::EncodedHeader {
-        let decoded = encoded_header::read_encoded_header(&mut buf)?;
+        let decoded = encoded_header::read_encoded_header(&mut next_header_buf, data, options)?;
         let mut header_buf = io::Cursor::new(&decoded);
         nid = nid::read_nid(&mut header_buf)?;
 
         if nid == NID::Header {
-            let header = header::read_header(&mut header_buf)?;
-            return read_archive_contents(header, &mut buf);
+            return header::read_header(&mut header_buf, data, options);
         }
     }
 
     if nid == NID::Header {
-        let header = header::read_header(&mut buf)?;
-        return read_archive_contents(header, &mut buf);
+        return header::read_header(&mut next_header_buf, data, options);
     }
 
-    return Err(ArchiveError::new(&format!("Unexpected NID {:?}", nid)));
+    Err(ArchiveError::new(&format!("Unexpected NID {:?}", nid)))
 }
 
 #[derive(Debug)]
 pub struct File {
     pub name: String,
-    pub data: Vec<u8>
+    pub data: Vec<u8>,
+    /// Only ever set with `Verification::Lenient`
+    pub crc_mismatch: bool,
+    /// The coders of the file's folder in decoding order, with their properties (e.g. "LZMA2:24 BCJ")
+    pub method: String,
 }
 
-fn read_archive_contents<'a, R>(header: Header, buf: &mut R) -> Result<InternalArchive, ArchiveError> where R: io::BufRead, R: io::Seek {
-    let stream_offsets = header::get_stream_offsets(&header);
-    println!("Stream offsets: {:?}", stream_offsets);
+#[derive(Debug, Clone, PartialEq)]
+pub enum TestStatus {
+    Ok,
+    CrcMismatch,
+    DecodeError(String),
+}
 
-    let mut data: Vec<File> = Vec::new();
+#[derive(Debug)]
+pub struct TestEntry {
+    pub name: String,
+    pub status: TestStatus,
+}
 
-    let mut decoded_folders: Vec<Vec<u8>> = Vec::with_capacity(header.streams_info.folders.len());
+#[derive(Debug)]
+pub struct TestReport {
+    pub entries: Vec<TestEntry>,
+}
+impl TestReport {
+    pub fn is_ok(&self) -> bool {
+        self.entries.iter().all(|entry| entry.status == TestStatus::Ok)
+    }
+}
 
-    let num_folders = header.streams_info.folders.len();
-    for folder_index in 0..num_folders {
-        let folder = &header.streams_info.folders[folder_index];
+struct DecodedFolder {
+    data: Vec<u8>,
+    crc_mismatch: bool,
+    encrypted: bool,
+}
 
-        let first_pack_stream_index = header.stream_map.folder_first_pack_stream_index[folder_index];
-        let folder_buf_offset =
-            SIGNATURE_HEADER_SIZE as u64
-                + header.streams_info.pack_info.pack_pos
-                + header.stream_map.pack_stream_offsets[first_pack_stream_index] as u64;
+struct EntryContents<'a> {
+    data: &'a [u8],
+    crc_mismatch: bool,
+}
 
-        let compressed_size = header.streams_info.pack_info.pack_sizes[folder_index];
+// Fails in strict mode, and otherwise tells whether the mismatch should be flagged
+fn on_crc_mismatch(options: &DecompressOptions, error: ArchiveError) -> Result<bool, ArchiveError> {
+    match options.verification {
+        Verification::Strict => Err(error),
+        Verification::Lenient => Ok(true),
+        Verification::Off => Ok(false),
+    }
+}
 
-        or_archive_error(buf.seek(io::SeekFrom::Start(folder_buf_offset)))?;
-        // buf.set_position(folder_buf_offset as usize);
-        let mut reader = vec![0u8; compressed_size as usize];
-        or_archive_error(buf.read_exact(&mut reader))?;
+// AES-CBC has no authentication, so a wrong password only shows up as data that can't be decoded,
+// or that doesn't match its CRC
+fn check_password(encrypted: bool, error: ArchiveError) -> ArchiveError {
+    if !encrypted || error.kind == ErrorKind::PasswordRequired || error.kind == ErrorKind::MemoryLimitExceeded {
+        return error;
+    }
+    ArchiveError::with_kind(ErrorKind::WrongPassword, &format!("Wrong password ({})", error.message))
+}
 
-        let coders = folder.get_ordered_coders();
-        // just a little hack/shortcut; use the first coder
-        let coder = &coders[0].coder_options;
+// Methods that can't be decoded and invalid coder properties have nothing to do with the password.
+// They are checked before anything is decoded, so that only errors in decoding decrypted data are
+// blamed on the password.
+fn check_coders(folder: &Folder, options: &DecompressOptions) -> Result<(), ArchiveError> {
+    for coder_index in 0..folder.num_coders() {
+        let coder = &folder.get_coder(coder_index).coder_options;
+        options.codecs.find(&coder.decompression_method_id, folder.get_in_streams(coder_index)?.len())?;
+        methods::describe_properties(&coder.decompression_method_id, &coder.properties)?;
+    }
+    Ok(())
+}
 
-        let unpack_size = folder.unpack_sizes[0]; // .iter().sum();
+fn decode_main_coder(folder: &Folder, packed: &[&[u8]], options: &DecompressOptions) -> Result<Vec<u8>, ArchiveError> {
+    check_coders(folder, options)?;
+    decode_coder(folder, folder.get_main_coder()?, packed, options)
+}
 
-        let res = decode::decode(&coder.decompression_method_id, &reader, &coder.properties, unpack_size)?;
+// Decodes the output of a coder. Each of its inputs is either one of the folder's packed streams,
+// or (through a bind pair) the output of another coder, which gets decoded first.
+// The graph was checked to be a tree when the folder was read, so the recursion terminates.
+fn decode_coder(folder: &Folder, coder_index: usize, packed: &[&[u8]], options: &DecompressOptions) -> Result<Vec<u8>, ArchiveError> {
+    let inputs = folder.get_in_streams(coder_index)?.into_iter().map(|in_stream| match in_stream {
+        InStream::Packed(i) => Ok(Cow::Borrowed(packed[i])),
+        InStream::Coder(c) => decode_coder(folder, c, packed, options).map(Cow::Owned)
+    }).collect::<Result<Vec<_>, _>>()?;
+    let inputs: Vec<&[u8]> = inputs.iter().map(|input| input.as_ref()).collect();
+
+    let coder = &folder.get_coder(coder_index).coder_options;
+    let unpack_size = folder.unpack_sizes[folder.get_out_stream_index(coder_index)];
+    options.codecs.decode(&coder.decompression_method_id, &inputs, &coder.properties, unpack_size, options)
+        .map_err(|e| check_password(folder.reads_decrypted_data(coder_index), e))
+}
 
-        decoded_folders.push(res);
+// Packed streams are stored back to back, starting pack_pos bytes after the signature header.
+// `offset` is relative to the first packed stream.
+fn get_pack_stream(data: &[u8], pack_pos: u64, offset: u64, size: u64) -> Result<&[u8], ArchiveError> {
+    let out_of_bounds = || ArchiveError::with_kind(ErrorKind::HeaderCorrupt, &format!("Packed stream at offset {} ({} bytes) lies outside the archive", offset, size));
+    let start = SIGNATURE_HEADER_SIZE.checked_add(pack_pos).and_then(|s| s.checked_add(offset)).ok_or_else(out_of_bounds)?;
+    let end = start.checked_add(size).ok_or_else(out_of_bounds)?;
+    if end > data.len() as u64 {
+        return Err(out_of_bounds());
     }
+    Ok(&data[start as usize..end as usize])
+}
 
-    println!("Decoded all the folders!");
+fn decode_folder(header: &Header, data: &[u8], folder_index: usize, options: &DecompressOptions) -> Result<DecodedFolder, ArchiveError> {
+    let folder = &header.streams_info.folders[folder_index];
+    let pack_info = &header.streams_info.pack_info;
+    let verify = options.verification != Verification::Off;
+    let mut crc_mismatch = false;
+
+    // The folder's packed streams follow the ones of the previous folders, in the order of folder.packed_streams
+    let first_pack_stream_index = header.stream_map.folder_first_pack_stream_index[folder_index];
+    let mut packed: Vec<&[u8]> = Vec::with_capacity(folder.packed_streams.len());
+    for pack_stream_index in first_pack_stream_index..first_pack_stream_index + folder.packed_streams.len() {
+        let offset = header.stream_map.pack_stream_offsets[pack_stream_index];
+        let stream = get_pack_stream(data, pack_info.pack_pos, offset, pack_info.pack_sizes[pack_stream_index])?;
+        if verify && options.verify_pack_crcs && !pack_info.check_crc(pack_stream_index, stream) {
+            let error = ArchiveError::with_kind(ErrorKind::PackCrcMismatch, &format!("CRC mismatch in packed stream {}", pack_stream_index));
+            crc_mismatch |= on_crc_mismatch(options, error)?;
+        }
+        packed.push(stream);
+    }
 
-    for i in 0..stream_offsets.len() {
-        let entry = &stream_offsets[i];
-        let decoded_folder_data = &decoded_folders[entry.folder_index];
-        let mut buf = io::Cursor::new(decoded_folder_data);
-        // println!("Start = {}, Size = {}, End = {}, Available = {}", entry.offset, entry.size, entry.offset + entry.size, buf.len());
-        buf.set_position(entry.offset);
-        let mut result = vec![0u8; entry.size as usize];
-        // buf.read_u8().unwrap();
-        or_archive_error(buf.read_exact(&mut result))?;
-        data.push(File {
-            name: entry.name.to_string(),
-            data: result
-        });
+    let encrypted = folder.is_encrypted();
+    let res = decode_main_coder(folder, &packed, options)?;
+    if verify && !folder.check_crc(&res) {
+        let error = ArchiveError::with_kind(ErrorKind::FolderCrcMismatch, &format!("CRC mismatch in folder {}", folder_index));
+        crc_mismatch |= on_crc_mismatch(options, check_password(encrypted, error))?;
     }
+    Ok(DecodedFolder {
+        data: res,
+        crc_mismatch,
+        encrypted
+    })
+}
 
-    return Ok(InternalArchive{
-        files: data
-    });
+fn read_entry<'a>(entry: &Entry, folder: &'a DecodedFolder, options: &DecompressOptions) -> Result<EntryContents<'a>, ArchiveError> {
+    let data = entry.read(&folder.data)?;
+    let mut crc_mismatch = folder.crc_mismatch;
+    if options.verification != Verification::Off && !entry.check_crc(data) {
+        let error = ArchiveError::with_kind(ErrorKind::FileCrcMismatch, &format!("CRC mismatch for {}", entry.name));
+        crc_mismatch |= on_crc_mismatch(options, check_password(folder.encrypted, error))?;
+    }
+    Ok(EntryContents {
+        data,
+        crc_mismatch
+    })
+}
+
+// Hands the contents of every entry (or the error that prevented reading it) to `visit`.
+// The folders are decoded one at a time, so only a single decoded folder is held in memory.
+fn visit_entries<F>(header: &Header, data: &[u8], options: &DecompressOptions, mut visit: F) -> Result<(), ArchiveError>
+    where F: FnMut(&Entry, Result<EntryContents, ArchiveError>) -> Result<(), ArchiveError> {
+    let stream_offsets = header::get_stream_offsets(header);
+
+    let mut current_folder: Option<usize> = None;
+    let mut decoded_folder: Result<DecodedFolder, ArchiveError> = Err(ArchiveError::new("No folder decoded yet"));
+    for entry in stream_offsets.iter() {
+        if current_folder != Some(entry.folder_index) {
+            decoded_folder = decode_folder(header, data, entry.folder_index, options);
+            current_folder = Some(entry.folder_index);
+        }
+
+        let contents = match decoded_folder {
+            Ok(ref folder) => read_entry(entry, folder, options),
+            Err(ref e) => Err(e.clone())
+        };
+        visit(entry, contents)?;
+    }
+    Ok(())
 }
 
 #[cfg(test)]
@@ -171,7 +479,7 @@ mod tests {
         let bytes: [u8; 8] = [0x37, 0x7A, 0xBC, 0xAF, 0x27, 0x1C, 7, 8];
         let result = decompress(&bytes);
         assert!(result.is_err(), "Should be an error");
-        assert_eq!("The file is too small", result.err().expect("Should be an error").message);
+        assert_eq!("The file is too small", result.expect_err("Should be an error").message);
 
     }
 
@@ -180,7 +488,7 @@ mod tests {
         let bytes: [u8; 14] = [1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14];
         let result = decompress(&bytes);
         assert!(result.is_err(), "Should be an error");
-        assert_eq!("Signature mismatch", result.err().expect("Should be an error").message);
+        assert_eq!("Signature mismatch", result.expect_err("Should be an error").message);
     }
 
     #[test]
@@ -188,7 +496,7 @@ mod tests {
         let bytes: [u8; 14] = [0x37, 0x7A, 0xBC, 0xAF, 0x27, 0x1C, 1, 4, 0, 0, 0, 0, 0, 0];
         let result = decompress(&bytes);
         assert!(result.is_err(), "Should be an error");
-        assert_eq!("Unsupported 7z version (1,4)", result.err().expect("Should be an error").message);
+        assert_eq!("Unsupported 7z version (1,4)", result.expect_err("Should be an error").message);
     }
 
     #[test]
@@ -201,4 +509,488 @@ mod tests {
         assert_eq!(std::str::from_utf8(&result.files[1].data).unwrap(), "dogdogdogdogdog\n");
         Ok(())
     }
+
+    #[test]
+    fn stored_files() -> Result<(), ArchiveError> {
+        let bytes = include_bytes!("../tests/copy.7z");
+        let result = decompress(bytes)?;
+        assert_eq!(result.files[0].name, "stored/a.png");
+        assert_eq!(result.files[0].data.len(), 768);
+        assert_eq!(result.files[0].data[255], 255);
+        assert_eq!(result.files[1].name, "stored/b.txt");
+        assert_eq!(std::str::from_utf8(&result.files[1].data).unwrap(), "already compressed, honest\n");
+        Ok(())
+    }
+
+    #[test]
+    fn deflate() -> Result<(), ArchiveError> {
+        let bytes = include_bytes!("../tests/deflate.7z");
+        let result = decompress(bytes)?;
+        assert_eq!(result.files[0].name, "deflate/one.txt");
+        assert_eq!(result.files[0].data, "Deflate is still everywhere.\n".repeat(50).as_bytes());
+        assert_eq!(result.files[1].name, "deflate/two.txt");
+        assert_eq!(result.files[1].data, "So is Windows.\n".repeat(30).as_bytes());
+        Ok(())
+    }
+
+    #[test]
+    fn deflate64() -> Result<(), ArchiveError> {
+        // Ends with a 5000 byte match 33000 bytes back, which needs both the 64 KiB window and the 16-bit length code
+        let bytes = include_bytes!("../tests/deflate64.7z");
+        let result = decompress(bytes)?;
+        let data = &result.files[0].data;
+        assert_eq!(data.len(), 38000);
+        assert_eq!(&data[33000..], &data[..5000]);
+        Ok(())
+    }
+
+    #[test]
+    fn bzip2_multiple_streams() -> Result<(), ArchiveError> {
+        let bytes = include_bytes!("../tests/bzip2.7z");
+        let result = decompress(bytes)?;
+        assert_eq!(result.files[0].name, "build/log.txt");
+        assert_eq!(result.files[0].data, "[info] compiling module\n".repeat(40).as_bytes());
+        assert_eq!(result.files[1].name, "build/out.csv");
+        assert!(result.files[1].data.ends_with(b"99,9801\n"), "Should include the second stream");
+        Ok(())
+    }
+
+    #[test]
+    fn ppmd() -> Result<(), ArchiveError> {
+        let bytes = include_bytes!("../tests/ppmd.7z");
+        let result = decompress(bytes)?;
+        assert_eq!(result.files[0].name, "logs/app.log");
+        assert!(result.files[0].data.starts_with(b"2020-07-12 10:00:00 INFO request served in 0 ms\n"));
+        assert_eq!(result.files[1].name, "dumps/table.csv");
+        assert!(result.files[1].data.ends_with(b"item149,447\n"));
+        Ok(())
+    }
+
+    #[test]
+    fn bcj_x86() -> Result<(), ArchiveError> {
+        let bytes = include_bytes!("../tests/bcj.7z");
+        let result = decompress(bytes)?;
+        assert_eq!(result.files[0].name, "bin/app.exe");
+        assert_eq!(result.files[0].data.len(), 20000);
+        assert_eq!(result.files[1].name, "bin/readme.txt");
+        assert_eq!(result.files[1].data, b"Run app.exe\n");
+        Ok(())
+    }
+
+    #[test]
+    fn branch_filters() -> Result<(), ArchiveError> {
+        // ARM + LZMA2, ARMT + Deflate, ARM64 (with a start offset) + LZMA2 and RISC-V + BZip2
+        let bytes = include_bytes!("../tests/branch_filters.7z");
+        let result = decompress(bytes)?;
+        let names: Vec<&str> = result.files.iter().map(|f| f.name.as_str()).collect();
+        assert_eq!(names, vec!["lib/arm.so", "lib/thumb.so", "lib/arm64.so", "lib/riscv.so"]);
+        for file in &result.files {
+            assert_eq!(file.data.len(), 8000);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn legacy_branch_filters() -> Result<(), ArchiveError> {
+        // PPC + LZMA, SPARC + LZMA2 and IA64 + LZMA2
+        let bytes = include_bytes!("../tests/legacy_filters.7z");
+        let result = decompress(bytes)?;
+        let names: Vec<&str> = result.files.iter().map(|f| f.name.as_str()).collect();
+        assert_eq!(names, vec!["bin/ppc.o", "bin/sparc.o", "bin/ia64.o"]);
+        for file in &result.files {
+            assert_eq!(file.data.len(), 8000);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn delta() -> Result<(), ArchiveError> {
+        // Delta:4 + LZMA2 over 16-bit stereo samples
+        let bytes = include_bytes!("../tests/delta.7z");
+        let result = decompress(bytes)?;
+        assert_eq!(result.files[0].name, "audio/tone.pcm");
+        assert_eq!(result.files[0].data.len(), 20000);
+        Ok(())
+    }
+
+    #[test]
+    fn bcj2() -> Result<(), ArchiveError> {
+        // BCJ2 reading from LZMA2 (main), two LZMA streams (call and jump), and a stored range coder stream
+        let bytes = include_bytes!("../tests/bcj2.7z");
+        let result = decompress(bytes)?;
+        assert_eq!(result.files[0].name, "setup/installer.exe");
+        assert_eq!(result.files[0].data.len(), 30000);
+        assert_eq!(result.files[1].name, "setup/license.txt");
+        assert_eq!(result.files[1].data, b"Do what you want\n");
+        Ok(())
+    }
+
+    #[test]
+    fn coder_graph() -> Result<(), ArchiveError> {
+        // A Delta <- BCJ <- LZMA2 chain, and a folder whose main coder is listed after the coder feeding it
+        let bytes = include_bytes!("../tests/coder_graph.7z");
+        let result = decompress(bytes)?;
+        assert_eq!(result.files[0].name, "bin/code.bin");
+        assert_eq!(result.files[0].data.len(), 6010);
+        assert_eq!(result.files[1].name, "audio/tone.pcm");
+        assert_eq!(result.files[1].data.len(), 12000);
+        Ok(())
+    }
+
+    #[test]
+    fn multiple_pack_streams() -> Result<(), ArchiveError> {
+        // A BCJ2 folder with four packed streams, followed by an LZMA2 and a stored folder
+        let bytes = include_bytes!("../tests/multi_pack.7z");
+        let options = DecompressOptions { verify_pack_crcs: true, ..Default::default() };
+        let result = decompress_with_options(bytes, &options)?;
+        assert_eq!(result.files[0].name, "bin/tool.exe");
+        assert_eq!(result.files[0].data.len(), 8000);
+        assert_eq!(result.files[1].name, "README");
+        assert!(result.files[1].data.starts_with(b"Multiple folders"));
+        assert_eq!(result.files[2].name, "NOTES");
+        assert_eq!(result.files[2].data, b"stored as is\n");
+        Ok(())
+    }
+
+    #[test]
+    fn pack_stream_out_of_bounds() {
+        let data = [0u8; 40];
+        assert_eq!(super::get_pack_stream(&data, 4, 2, 2).unwrap().len(), 2);
+        assert!(super::get_pack_stream(&data, 4, 2, 3).is_err(), "Should return an error");
+        assert!(super::get_pack_stream(&data, u64::MAX, 2, 3).is_err(), "Should return an error");
+    }
+
+    #[test]
+    fn custom_decoder() -> Result<(), ArchiveError> {
+        // Method 7F 01 is made up: every byte is XORed with the property byte
+        let bytes = include_bytes!("../tests/custom_method.7z");
+        assert!(decompress(bytes).is_err(), "Should return an error");
+
+        let mut options = DecompressOptions::default();
+        options.codecs.register(&[0x7F, 0x01], |reader: &[u8], properties: &[u8], _unpack_size: u64, _options: &DecompressOptions| {
+            Ok(reader.iter().map(|b| b ^ properties[0]).collect())
+        });
+        let result = decompress_with_options(bytes, &options)?;
+        assert_eq!(result.files[0].data, b"Only readable with the custom decoder\n");
+        Ok(())
+    }
+
+    #[test]
+    fn replace_builtin_decoder() {
+        let bytes = include_bytes!("../tests/copy.7z");
+        let mut options = DecompressOptions::default();
+        options.codecs.register(&[0x00], |_: &[u8], _: &[u8], _: u64, _: &DecompressOptions| {
+            Err(ArchiveError::new("Replaced"))
+        });
+        let result = decompress_with_options(bytes, &options);
+        assert_eq!(result.err().unwrap().message, "Replaced");
+    }
+
+    #[test]
+    fn huge_dictionary() -> Result<(), ArchiveError> {
+        // The LZMA2 coder claims a 4 GiB dictionary, but only needs as much as the file is long
+        let bytes = include_bytes!("../tests/huge_dict.7z");
+        let result = decompress(bytes)?;
+        assert_eq!(result.files[0].data.len(), 1100);
+        Ok(())
+    }
+
+    #[test]
+    fn memory_limit() {
+        let bytes = include_bytes!("../tests/delta.7z");
+        let options = DecompressOptions { memory_limit: Some(1024), ..Default::default() };
+        let result = decompress_with_options(bytes, &options);
+        assert_eq!(result.err().unwrap().kind, ErrorKind::MemoryLimitExceeded);
+    }
+
+    #[test]
+    fn method_descriptions() -> Result<(), ArchiveError> {
+        let result = decompress(include_bytes!("../tests/coder_graph.7z"))?;
+        assert_eq!(result.files[0].method, "LZMA2:20 BCJ Delta:2");
+        assert_eq!(result.files[1].method, "LZMA2:20 Delta:4");
+        let result = decompress(include_bytes!("../tests/bcj2.7z"))?;
+        assert_eq!(result.files[0].method, "LZMA2:20 LZMA:16:lc0:lp2 LZMA:16:lc0:lp2 BCJ2");
+        Ok(())
+    }
+
+    #[test]
+    fn additional_streams() -> Result<(), ArchiveError> {
+        // The file names, attributes and folder description are stored in additional streams
+        let bytes = include_bytes!("../tests/additional_streams.7z");
+        let result = decompress(bytes)?;
+        assert_eq!(result.files[0].name, "docs/external.txt");
+        assert_eq!(result.files[0].data, b"File names from an additional stream\n");
+        assert_eq!(result.files[1].name, "docs/readonly.txt");
+        assert_eq!(result.files[1].data, b"Attributes too\n");
+        Ok(())
+    }
+
+    fn with_password(password: &str) -> DecompressOptions {
+        DecompressOptions {
+            password: Some(password.to_string()),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn aes() -> Result<(), ArchiveError> {
+        let bytes = include_bytes!("../tests/aes.7z");
+        let result = decompress_with_options(bytes, &with_password("correct horse"))?;
+        assert_eq!(result.files[0].name, "secret/plans.txt");
+        assert!(result.files[0].data.starts_with(b"The treasure is buried"));
+        assert_eq!(result.files[1].name, "secret/key.txt");
+        assert_eq!(result.files[1].data, b"hunter2\n");
+        Ok(())
+    }
+
+    #[test]
+    fn aes_without_password() {
+        let bytes = include_bytes!("../tests/aes.7z");
+        let result = decompress(bytes);
+        assert_eq!(result.err().unwrap().kind, ErrorKind::PasswordRequired);
+    }
+
+    #[test]
+    fn aes_wrong_password() {
+        let bytes = include_bytes!("../tests/aes.7z");
+        let result = decompress_with_options(bytes, &with_password("battery staple"));
+        assert_eq!(result.err().unwrap().kind, ErrorKind::WrongPassword);
+    }
+
+    #[test]
+    fn aes_invalid_properties() {
+        // The key derivation cycles power is 30, more than 7-Zip accepts
+        let bytes = include_bytes!("../tests/aes_bad_properties.7z");
+        let result = decompress_with_options(bytes, &with_password("correct horse"));
+        let error = result.err().unwrap();
+        assert_eq!(error.kind, ErrorKind::Other);
+        assert_eq!(error.message, "Unsupported AES key derivation cycles power 30");
+    }
+
+    #[test]
+    fn aes_unsupported_method() {
+        // The LZMA2 coder behind AES can't be decoded, whatever the password
+        struct TwoInputs;
+        impl codec::Decoder for TwoInputs {
+            fn num_in_streams(&self) -> usize {
+                2
+            }
+
+            fn decode(&self, _: &[&[u8]], _: &[u8], _: u64, _: &DecompressOptions) -> Result<Vec<u8>, ArchiveError> {
+                Ok(Vec::new())
+            }
+        }
+        let bytes = include_bytes!("../tests/aes.7z");
+        let mut options = with_password("battery staple");
+        options.codecs.register(&[0x21], TwoInputs);
+        let result = decompress_with_options(bytes, &options);
+        assert_eq!(result.err().unwrap().kind, ErrorKind::Other);
+    }
+
+    #[test]
+    fn encrypted_header() -> Result<(), ArchiveError> {
+        let bytes = include_bytes!("../tests/encrypted_header.7z");
+        let result = decompress_with_options(bytes, &with_password("correct horse"))?;
+        assert_eq!(result.files[0].name, "confidential/q3-report.txt");
+        assert!(result.files[0].data.starts_with(b"Revenue is up"));
+        assert_eq!(result.files[1].name, "confidential/partners.csv");
+        assert_eq!(result.files[1].data, b"name,share\nacme,50\nglobex,50\n");
+        Ok(())
+    }
+
+    #[test]
+    fn encrypted_header_without_password() {
+        let bytes = include_bytes!("../tests/encrypted_header.7z");
+        let result = decompress(bytes);
+        assert_eq!(result.err().unwrap().kind, ErrorKind::PasswordRequired);
+    }
+
+    #[test]
+    fn encrypted_header_wrong_password() {
+        let bytes = include_bytes!("../tests/encrypted_header.7z");
+        let result = decompress_with_options(bytes, &with_password("battery staple"));
+        assert_eq!(result.err().unwrap().kind, ErrorKind::WrongPassword);
+    }
+
+    #[cfg(any(feature = "zstd", feature = "lz4", feature = "brotli"))]
+    fn assert_zs_files(result: InternalArchive) {
+        assert_eq!(result.files[0].name, "zs/readme.txt");
+        assert!(result.files[0].data.starts_with(b"Line 0 of the 7-Zip ZS readme"));
+        assert_eq!(result.files[1].name, "zs/numbers.csv");
+        assert!(result.files[1].data.ends_with(b"399,159201\n"));
+    }
+
+    #[cfg(feature = "zstd")]
+    #[test]
+    fn zstd() -> Result<(), ArchiveError> {
+        let bytes = include_bytes!("../tests/zstd.7z");
+        assert_zs_files(decompress(bytes)?);
+        Ok(())
+    }
+
+    #[cfg(feature = "zstd")]
+    #[test]
+    fn zstd_large_folder() -> Result<(), ArchiveError> {
+        // 20 MiB, more than the output buffer is allocated with up front
+        let bytes = include_bytes!("../tests/zstd_large.7z");
+        let result = decompress(bytes)?;
+        assert_eq!(result.files[0].data.len(), 20 << 20);
+        Ok(())
+    }
+
+    #[cfg(feature = "lz4")]
+    #[test]
+    fn lz4() -> Result<(), ArchiveError> {
+        let bytes = include_bytes!("../tests/lz4.7z");
+        assert_zs_files(decompress(bytes)?);
+        Ok(())
+    }
+
+    #[cfg(feature = "brotli")]
+    #[test]
+    fn brotli() -> Result<(), ArchiveError> {
+        let bytes = include_bytes!("../tests/brotli.7z");
+        assert_zs_files(decompress(bytes)?);
+        Ok(())
+    }
+
+    fn with_corrupt_next_header_offset() -> Vec<u8> {
+        let mut bytes = include_bytes!("../tests/foobar.7z").to_vec();
+        bytes[12] ^= 0x01;
+        bytes
+    }
+
+    #[test]
+    fn corrupt_start_header() {
+        let result = decompress(&with_corrupt_next_header_offset());
+        assert!(result.is_err(), "Should be an error");
+        assert_eq!(ErrorKind::StartHeaderCorrupt, result.expect_err("Should be an error").kind);
+    }
+
+    #[test]
+    fn corrupt_next_header() {
+        let mut bytes = include_bytes!("../tests/foobar.7z").to_vec();
+        let len = bytes.len();
+        bytes[len - 3] ^= 0x01;
+        let result = decompress(&bytes);
+        assert!(result.is_err(), "Should be an error");
+        let error = result.expect_err("Should be an error");
+        assert_eq!(ErrorKind::HeaderCorrupt, error.kind);
+        assert_eq!("Next header CRC mismatch", error.message);
+    }
+
+    #[test]
+    fn corrupt_file_data() {
+        let mut bytes = include_bytes!("../tests/foobar.7z").to_vec();
+        bytes[39] ^= 0x01;
+        let result = decompress(&bytes);
+        assert!(result.is_err(), "Should be an error");
+        let error = result.expect_err("Should be an error");
+        assert_eq!(ErrorKind::FileCrcMismatch, error.kind);
+        assert_eq!("CRC mismatch for foobar/hello.txt", error.message);
+    }
+
+    #[test]
+    fn corrupt_encoded_header_folder() {
+        let mut bytes = include_bytes!("../tests/foobar.7z").to_vec();
+        bytes[176] ^= 0x01;
+        let result = decompress(&bytes);
+        assert!(result.is_err(), "Should be an error");
+        assert_eq!(ErrorKind::FolderCrcMismatch, result.expect_err("Should be an error").kind);
+    }
+
+    #[test]
+    fn verify_pack_crcs() -> Result<(), ArchiveError> {
+        let bytes = include_bytes!("../tests/pack_crc.7z");
+        let options = DecompressOptions { verify_pack_crcs: true, ..Default::default() };
+        let result = decompress_with_options(bytes, &options)?;
+        assert_eq!(result.files[0].name, "fox.txt");
+        Ok(())
+    }
+
+    #[test]
+    fn corrupt_packed_stream() {
+        let mut bytes = include_bytes!("../tests/pack_crc.7z").to_vec();
+        bytes[60] ^= 0x01;
+        let options = DecompressOptions { verify_pack_crcs: true, ..Default::default() };
+        let result = decompress_with_options(&bytes, &options);
+        assert!(result.is_err(), "Should be an error");
+        let error = result.expect_err("Should be an error");
+        assert_eq!(ErrorKind::PackCrcMismatch, error.kind);
+        assert_eq!("CRC mismatch in packed stream 0", error.message);
+    }
+
+    #[test]
+    fn test_intact_archive() -> Result<(), ArchiveError> {
+        let bytes = include_bytes!("../tests/foobar.7z");
+        let report = test_archive(bytes, &DecompressOptions::default())?;
+        assert!(report.is_ok(), "Should pass");
+        assert_eq!(report.entries.len(), 2);
+        assert_eq!(report.entries[1].name, "foobar/world.txt");
+        Ok(())
+    }
+
+    #[test]
+    fn test_corrupt_file_data() -> Result<(), ArchiveError> {
+        let mut bytes = include_bytes!("../tests/foobar.7z").to_vec();
+        bytes[39] ^= 0x01;
+        let report = test_archive(&bytes, &DecompressOptions::default())?;
+        assert!(!report.is_ok(), "Should fail");
+        assert_eq!(report.entries[0].status, TestStatus::CrcMismatch);
+        Ok(())
+    }
+
+    #[test]
+    fn test_undecodable_folder() -> Result<(), ArchiveError> {
+        let mut bytes = include_bytes!("../tests/foobar.7z").to_vec();
+        bytes[33] ^= 0x01;
+        let report = test_archive(&bytes, &DecompressOptions::default())?;
+        for entry in report.entries {
+            match entry.status {
+                TestStatus::DecodeError(_) => {},
+                status => panic!("Expected a decode error, got {:?}", status)
+            }
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn lenient_verification() -> Result<(), ArchiveError> {
+        let mut bytes = include_bytes!("../tests/foobar.7z").to_vec();
+        bytes[39] ^= 0x01;
+        let options = DecompressOptions { verification: Verification::Lenient, ..Default::default() };
+        let result = decompress_with_options(&bytes, &options)?;
+        assert_eq!(result.files[0].name, "foobar/hello.txt");
+        assert!(result.files[0].crc_mismatch, "Should be flagged");
+        Ok(())
+    }
+
+    #[test]
+    fn lenient_verification_of_folders() -> Result<(), ArchiveError> {
+        let mut bytes = include_bytes!("../tests/pack_crc.7z").to_vec();
+        bytes[40] ^= 0x01;
+        let options = DecompressOptions { verification: Verification::Lenient, verify_pack_crcs: true, ..Default::default() };
+        let result = decompress_with_options(&bytes, &options)?;
+        assert!(result.files[0].crc_mismatch, "Should be flagged");
+        Ok(())
+    }
+
+    #[test]
+    fn verification_off() -> Result<(), ArchiveError> {
+        let mut bytes = include_bytes!("../tests/foobar.7z").to_vec();
+        bytes[39] ^= 0x01;
+        let options = DecompressOptions { verification: Verification::Off, ..Default::default() };
+        let result = decompress_with_options(&bytes, &options)?;
+        assert!(!result.files[0].crc_mismatch, "Shouldn't be checked");
+        Ok(())
+    }
+
+    #[test]
+    fn recover_corrupt_start_header() -> Result<(), ArchiveError> {
+        let options = DecompressOptions { recover_start_header: true, ..Default::default() };
+        let result = decompress_with_options(&with_corrupt_next_header_offset(), &options)?;
+        assert_eq!(result.files[0].name, "foobar/hello.txt");
+        assert_eq!(std::str::from_utf8(&result.files[0].data).unwrap(), "catcatcatcat\n");
+        Ok(())
+    }
 }
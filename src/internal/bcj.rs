@@ -0,0 +1,282 @@
+// Branch converters (BCJ filters). The encoder turned the relative addresses of
+// call/jump instructions into absolute ones, so they compress better; decoding turns
+// them back. `start_offset` is the position of the first byte in the original stream.
+
+use super::byteorder::{BigEndian, ByteOrder, LittleEndian};
+
+fn test_x86_ms_byte(b: u8) -> bool {
+    b == 0 || b == 0xFF
+}
+
+pub fn x86_decode(buffer: &mut [u8], start_offset: u32) {
+    const MASK_TO_ALLOWED_STATUS: [bool; 8] = [true, true, true, false, true, false, false, false];
+    const MASK_TO_BIT_NUMBER: [u32; 8] = [0, 1, 2, 2, 3, 3, 3, 3];
+
+    if buffer.len() < 5 {
+        return;
+    }
+    let mut prev_mask: u32 = 0;
+    let mut prev_pos: u32 = start_offset.wrapping_sub(5);
+    let limit = buffer.len() - 5;
+    let mut pos = 0;
+    while pos <= limit {
+        let b = buffer[pos];
+        if b != 0xE8 && b != 0xE9 {
+            pos += 1;
+            continue;
+        }
+        let now = start_offset.wrapping_add(pos as u32);
+        let offset = now.wrapping_sub(prev_pos);
+        prev_pos = now;
+        if offset > 5 {
+            prev_mask = 0;
+        } else {
+            for _ in 0..offset {
+                prev_mask &= 0x77;
+                prev_mask <<= 1;
+            }
+        }
+
+        let b = buffer[pos + 4];
+        if test_x86_ms_byte(b) && MASK_TO_ALLOWED_STATUS[((prev_mask >> 1) & 0x7) as usize] && (prev_mask >> 1) < 0x10 {
+            let mut src = (b as u32) << 24
+                | (buffer[pos + 3] as u32) << 16
+                | (buffer[pos + 2] as u32) << 8
+                | buffer[pos + 1] as u32;
+            let mut dest;
+            loop {
+                dest = src.wrapping_sub(now.wrapping_add(5));
+                if prev_mask == 0 {
+                    break;
+                }
+                let i = MASK_TO_BIT_NUMBER[(prev_mask >> 1) as usize];
+                if !test_x86_ms_byte((dest >> (24 - i * 8)) as u8) {
+                    break;
+                }
+                src = dest ^ ((1u32 << (32 - i * 8)) - 1);
+            }
+            buffer[pos + 4] = !(((dest >> 24) & 1) as u8).wrapping_sub(1);
+            buffer[pos + 3] = (dest >> 16) as u8;
+            buffer[pos + 2] = (dest >> 8) as u8;
+            buffer[pos + 1] = dest as u8;
+            pos += 5;
+            prev_mask = 0;
+        } else {
+            pos += 1;
+            prev_mask |= 1;
+            if test_x86_ms_byte(b) {
+                prev_mask |= 0x10;
+            }
+        }
+    }
+}
+
+pub fn arm_decode(buffer: &mut [u8], start_offset: u32) {
+    let mut i = 0;
+    while i + 4 <= buffer.len() {
+        // BL instruction
+        if buffer[i + 3] == 0xEB {
+            let src = ((buffer[i + 2] as u32) << 16 | (buffer[i + 1] as u32) << 8 | buffer[i] as u32) << 2;
+            let dest = src.wrapping_sub(start_offset.wrapping_add(i as u32 + 8)) >> 2;
+            buffer[i + 2] = (dest >> 16) as u8;
+            buffer[i + 1] = (dest >> 8) as u8;
+            buffer[i] = dest as u8;
+        }
+        i += 4;
+    }
+}
+
+pub fn armt_decode(buffer: &mut [u8], start_offset: u32) {
+    let mut i = 0;
+    while i + 4 <= buffer.len() {
+        // The two halves of a Thumb BL instruction
+        if (buffer[i + 1] & 0xF8) == 0xF0 && (buffer[i + 3] & 0xF8) == 0xF8 {
+            let src = ((buffer[i + 1] as u32 & 7) << 19
+                | (buffer[i] as u32) << 11
+                | (buffer[i + 3] as u32 & 7) << 8
+                | buffer[i + 2] as u32) << 1;
+            let dest = src.wrapping_sub(start_offset.wrapping_add(i as u32 + 4)) >> 1;
+            buffer[i + 1] = 0xF0 | ((dest >> 19) & 0x7) as u8;
+            buffer[i] = (dest >> 11) as u8;
+            buffer[i + 3] = 0xF8 | ((dest >> 8) & 0x7) as u8;
+            buffer[i + 2] = dest as u8;
+            i += 2;
+        }
+        i += 2;
+    }
+}
+
+pub fn arm64_decode(buffer: &mut [u8], start_offset: u32) {
+    let mut i = 0;
+    while i + 4 <= buffer.len() {
+        let pc = start_offset.wrapping_add(i as u32);
+        let instr = LittleEndian::read_u32(&buffer[i..i + 4]);
+        if (instr >> 26) == 0x25 {
+            // BL
+            let dest = instr.wrapping_sub(pc >> 2);
+            LittleEndian::write_u32(&mut buffer[i..i + 4], 0x9400_0000 | (dest & 0x03FF_FFFF));
+        } else if (instr & 0x9F00_0000) == 0x9000_0000 {
+            // ADRP, only converted within +/-512 MiB
+            let src = ((instr >> 29) & 3) | ((instr >> 3) & 0x001F_FFFC);
+            if (src.wrapping_add(0x0002_0000) & 0x001C_0000) == 0 {
+                let dest = src.wrapping_sub(pc >> 12);
+                let instr = (instr & 0x9000_001F)
+                    | (dest & 3) << 29
+                    | (dest & 0x0003_FFFC) << 3
+                    | (0u32.wrapping_sub(dest & 0x0002_0000) & 0x00E0_0000);
+                LittleEndian::write_u32(&mut buffer[i..i + 4], instr);
+            }
+        }
+        i += 4;
+    }
+}
+
+pub fn riscv_decode(buffer: &mut [u8], start_offset: u32) {
+    if buffer.len() < 8 {
+        return;
+    }
+    let limit = buffer.len() - 8;
+    let mut i = 0;
+    while i <= limit {
+        let pc = start_offset.wrapping_add(i as u32);
+        let inst = buffer[i] as u32;
+        if inst == 0xEF {
+            // JAL
+            let b1 = buffer[i + 1] as u32;
+            if (b1 & 0x0D) != 0 {
+                i += 2;
+                continue;
+            }
+            let b2 = buffer[i + 2] as u32;
+            let b3 = buffer[i + 3] as u32;
+            let addr = ((b1 & 0xF0) << 13 | b2 << 9 | b3 << 1).wrapping_sub(pc);
+            buffer[i + 1] = ((b1 & 0x0F) | ((addr >> 8) & 0xF0)) as u8;
+            buffer[i + 2] = (((addr >> 16) & 0x0F) | ((addr >> 7) & 0x10) | ((addr << 4) & 0xE0)) as u8;
+            buffer[i + 3] = (((addr >> 4) & 0x7F) | ((addr >> 13) & 0x80)) as u8;
+            i += 4;
+        } else if (inst & 0x7F) == 0x17 {
+            // AUIPC
+            let mut inst = LittleEndian::read_u32(&buffer[i..i + 4]);
+            let inst2;
+            if inst & 0xE80 != 0 {
+                // AUIPC followed by an instruction using its result
+                let second = LittleEndian::read_u32(&buffer[i + 4..i + 8]);
+                if ((inst << 8) ^ second.wrapping_sub(3)) & 0xF8003 != 0 {
+                    i += 6;
+                    continue;
+                }
+                let addr = (inst & 0xFFFF_F000).wrapping_add(second >> 20);
+                inst = 0x17 | (2 << 7) | (second << 12);
+                inst2 = addr;
+            } else {
+                // A pair the encoder rewrote so it can be told apart from the above
+                let inst2_rs1 = inst >> 27;
+                if (inst.wrapping_sub(0x3117) << 18) >= (inst2_rs1 & 0x1D) {
+                    i += 4;
+                    continue;
+                }
+                let addr = BigEndian::read_u32(&buffer[i + 4..i + 8]).wrapping_sub(pc);
+                inst2 = (inst >> 12) | (addr << 20);
+                inst = 0x17 | (inst2_rs1 << 7) | (addr.wrapping_add(0x800) & 0xFFFF_F000);
+            }
+            LittleEndian::write_u32(&mut buffer[i..i + 4], inst);
+            LittleEndian::write_u32(&mut buffer[i + 4..i + 8], inst2);
+            i += 8;
+        } else {
+            i += 2;
+        }
+    }
+}
+
+pub fn ppc_decode(buffer: &mut [u8], start_offset: u32) {
+    let mut i = 0;
+    while i + 4 <= buffer.len() {
+        // Big endian "bl" (branch with link, absolute bit clear)
+        if (buffer[i] >> 2) == 0x12 && (buffer[i + 3] & 3) == 1 {
+            let src = BigEndian::read_u32(&buffer[i..i + 4]) & 0x03FF_FFFC;
+            let dest = src.wrapping_sub(start_offset.wrapping_add(i as u32));
+            buffer[i] = 0x48 | ((dest >> 24) & 0x03) as u8;
+            buffer[i + 1] = (dest >> 16) as u8;
+            buffer[i + 2] = (dest >> 8) as u8;
+            buffer[i + 3] = (buffer[i + 3] & 0x03) | dest as u8;
+        }
+        i += 4;
+    }
+}
+
+pub fn sparc_decode(buffer: &mut [u8], start_offset: u32) {
+    let mut i = 0;
+    while i + 4 <= buffer.len() {
+        // "call" with a displacement within +/-8 MiB
+        if (buffer[i] == 0x40 && (buffer[i + 1] & 0xC0) == 0x00) || (buffer[i] == 0x7F && (buffer[i + 1] & 0xC0) == 0xC0) {
+            let src = BigEndian::read_u32(&buffer[i..i + 4]) << 2;
+            let dest = src.wrapping_sub(start_offset.wrapping_add(i as u32)) >> 2;
+            let dest = ((0u32.wrapping_sub((dest >> 22) & 1) << 22) & 0x3FFF_FFFF)
+                | (dest & 0x3F_FFFF)
+                | 0x4000_0000;
+            BigEndian::write_u32(&mut buffer[i..i + 4], dest);
+        }
+        i += 4;
+    }
+}
+
+pub fn ia64_decode(buffer: &mut [u8], start_offset: u32) {
+    // Which of the three 41-bit slots of a 16 byte bundle can hold a branch, by bundle template
+    const BRANCH_TABLE: [u32; 32] = [
+        0, 0, 0, 0, 0, 0, 0, 0,
+        0, 0, 0, 0, 0, 0, 0, 0,
+        4, 4, 6, 6, 0, 0, 7, 7,
+        4, 4, 0, 0, 4, 4, 0, 0
+    ];
+
+    let mut i = 0;
+    while i + 16 <= buffer.len() {
+        let mask = BRANCH_TABLE[(buffer[i] & 0x1F) as usize];
+        for slot in 0..3 {
+            if (mask >> slot) & 1 == 0 {
+                continue;
+            }
+            let bit_pos = 5 + 41 * slot;
+            let byte_pos = i + (bit_pos >> 3);
+            let bit_res = bit_pos & 0x7;
+            let mut instruction: u64 = 0;
+            for j in 0..6 {
+                instruction |= (buffer[byte_pos + j] as u64) << (8 * j);
+            }
+
+            let mut inst_norm = instruction >> bit_res;
+            if ((inst_norm >> 37) & 0xF) == 0x5 && ((inst_norm >> 9) & 0x7) == 0 {
+                let src = (((inst_norm >> 13) & 0xF_FFFF) as u32 | (((inst_norm >> 36) & 1) as u32) << 20) << 4;
+                let dest = src.wrapping_sub(start_offset.wrapping_add(i as u32)) >> 4;
+                inst_norm &= !(0x8F_FFFFu64 << 13);
+                inst_norm |= ((dest & 0xF_FFFF) as u64) << 13;
+                inst_norm |= ((dest & 0x10_0000) as u64) << (36 - 20);
+
+                instruction &= (1u64 << bit_res) - 1;
+                instruction |= inst_norm << bit_res;
+                for j in 0..6 {
+                    buffer[byte_pos + j] = (instruction >> (8 * j)) as u8;
+                }
+            }
+        }
+        i += 16;
+    }
+}
+
+mod tests {
+    #[test]
+    fn x86_call() {
+        // call rel32 at position 0x10, encoded with the absolute target 0x1234 + 0x15
+        let mut buffer = vec![0x90u8; 0x20];
+        buffer[0x10..0x15].copy_from_slice(&[0xE8, 0x49, 0x12, 0x00, 0x00]);
+        super::x86_decode(&mut buffer, 0);
+        assert_eq!(buffer[0x10..0x15], [0xE8, 0x34, 0x12, 0x00, 0x00]);
+    }
+
+    #[test]
+    fn x86_leaves_short_buffers_alone() {
+        let mut buffer = vec![0xE8, 0x00, 0x00, 0x00];
+        super::x86_decode(&mut buffer, 0);
+        assert_eq!(buffer, [0xE8, 0x00, 0x00, 0x00]);
+    }
+}
@@ -0,0 +1,117 @@
+// BCJ2 splits the x86 CALL (E8) and JMP (E9, 0F 8x) targets into their own streams.
+// The decoder reads four inputs: the main stream, the CALL targets, the JMP targets
+// (both as big endian absolute addresses), and a range coded stream that tells
+// for every candidate instruction whether its target was converted.
+use internal::ArchiveError;
+use internal::decode;
+use super::byteorder::{BigEndian, ByteOrder};
+
+const NUM_TOP_BITS: u32 = 24;
+const TOP_VALUE: u32 = 1 << NUM_TOP_BITS;
+const NUM_BIT_MODEL_TOTAL_BITS: u32 = 11;
+const BIT_MODEL_TOTAL: u16 = 1 << NUM_BIT_MODEL_TOTAL_BITS;
+const NUM_MOVE_BITS: u32 = 5;
+
+fn truncated(stream: &str) -> ArchiveError {
+    ArchiveError::new(&format!("BCJ2 error: the {} stream is truncated", stream))
+}
+
+struct RangeDecoder<'a> {
+    input: &'a [u8],
+    pos: usize,
+    range: u32,
+    code: u32,
+}
+
+impl<'a> RangeDecoder<'a> {
+    fn new(input: &'a [u8]) -> Result<RangeDecoder<'a>, ArchiveError> {
+        let mut decoder = RangeDecoder { input, pos: 0, range: 0xFFFF_FFFF, code: 0 };
+        for _ in 0..5 {
+            decoder.code = (decoder.code << 8) | decoder.next_byte()? as u32;
+        }
+        Ok(decoder)
+    }
+
+    fn next_byte(&mut self) -> Result<u8, ArchiveError> {
+        let b = *self.input.get(self.pos).ok_or_else(|| truncated("range coder"))?;
+        self.pos += 1;
+        Ok(b)
+    }
+
+    fn decode_bit(&mut self, prob: &mut u16) -> Result<bool, ArchiveError> {
+        let bound = (self.range >> NUM_BIT_MODEL_TOTAL_BITS) * (*prob as u32);
+        let bit = if self.code < bound {
+            self.range = bound;
+            *prob += (BIT_MODEL_TOTAL - *prob) >> NUM_MOVE_BITS;
+            false
+        } else {
+            self.range -= bound;
+            self.code -= bound;
+            *prob -= *prob >> NUM_MOVE_BITS;
+            true
+        };
+        if self.range < TOP_VALUE {
+            self.range <<= 8;
+            self.code = (self.code << 8) | self.next_byte()? as u32;
+        }
+        Ok(bit)
+    }
+}
+
+fn is_jump(b0: u8, b1: u8) -> bool {
+    (b1 & 0xFE) == 0xE8 || (b0 == 0x0F && (b1 & 0xF0) == 0x80)
+}
+
+pub fn decode(main: &[u8], call: &[u8], jump: &[u8], range: &[u8], unpack_size: u64) -> Result<Vec<u8>, ArchiveError> {
+    let out_size = unpack_size as usize;
+    let mut out = decode::output_buffer(unpack_size);
+    let mut rc = RangeDecoder::new(range)?;
+    // One probability per preceding byte for E8, one for E9, and one for the 0F 8x jumps
+    let mut probs = [BIT_MODEL_TOTAL >> 1; 2 + 256];
+    let mut main_pos = 0;
+    let mut call_pos = 0;
+    let mut jump_pos = 0;
+    let mut prev_byte: u8 = 0;
+
+    while out.len() < out_size {
+        let b = *main.get(main_pos).ok_or_else(|| truncated("main"))?;
+        main_pos += 1;
+        out.push(b);
+        if !is_jump(prev_byte, b) {
+            prev_byte = b;
+            continue;
+        }
+        if out.len() == out_size {
+            break;
+        }
+
+        let prob = match b {
+            0xE8 => &mut probs[prev_byte as usize],
+            0xE9 => &mut probs[256],
+            _ => &mut probs[257]
+        };
+        if !rc.decode_bit(prob)? {
+            prev_byte = b;
+            continue;
+        }
+
+        let src = if b == 0xE8 {
+            let src = call.get(call_pos..call_pos + 4).ok_or_else(|| truncated("call"))?;
+            call_pos += 4;
+            BigEndian::read_u32(src)
+        } else {
+            let src = jump.get(jump_pos..jump_pos + 4).ok_or_else(|| truncated("jump"))?;
+            jump_pos += 4;
+            BigEndian::read_u32(src)
+        };
+        let dest = src.wrapping_sub(out.len() as u32 + 4);
+        for i in 0..4 {
+            if out.len() == out_size {
+                break;
+            }
+            out.push((dest >> (8 * i)) as u8);
+        }
+        prev_byte = (dest >> 24) as u8;
+    }
+    Ok(out)
+}
@@ -0,0 +1,114 @@
+// Decoders are looked up by their 7z method ID. The built-in ones are registered by default;
+// users can add their own, or replace a built-in one by registering the same method ID.
+use internal::{ArchiveError, DecompressOptions};
+use internal::decode;
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::Arc;
+
+// Native builds can share options (and their decoders) between threads. Decoders implemented
+// in JavaScript can't be sent to another thread, so the WebAssembly build doesn't require it.
+#[cfg(not(target_arch = "wasm32"))]
+pub trait MaybeSendSync: Send + Sync {}
+#[cfg(not(target_arch = "wasm32"))]
+impl<T> MaybeSendSync for T where T: Send + Sync {}
+
+#[cfg(target_arch = "wasm32")]
+pub trait MaybeSendSync {}
+#[cfg(target_arch = "wasm32")]
+impl<T> MaybeSendSync for T {}
+
+pub trait Decoder: MaybeSendSync {
+    /// How many input streams the coder reads. Most read a single one; BCJ2 reads four.
+    fn num_in_streams(&self) -> usize {
+        1
+    }
+
+    /// Decodes the inputs (packed streams, or the outputs of other coders) into `unpack_size` bytes.
+    /// `properties` are the coder properties stored in the archive.
+    fn decode(&self, inputs: &[&[u8]], properties: &[u8], unpack_size: u64, options: &DecompressOptions) -> Result<Vec<u8>, ArchiveError>;
+}
+
+// Any function that decodes a single input stream is a decoder
+impl<F> Decoder for F where F: Fn(&[u8], &[u8], u64, &DecompressOptions) -> Result<Vec<u8>, ArchiveError> + MaybeSendSync {
+    fn decode(&self, inputs: &[&[u8]], properties: &[u8], unpack_size: u64, options: &DecompressOptions) -> Result<Vec<u8>, ArchiveError> {
+        self(inputs[0], properties, unpack_size, options)
+    }
+}
+
+#[derive(Clone)]
+pub struct CodecRegistry {
+    decoders: HashMap<Vec<u8>, Arc<dyn Decoder>>,
+}
+
+impl CodecRegistry {
+    /// A registry with all the built-in decoders
+    pub fn new() -> CodecRegistry {
+        let mut registry = CodecRegistry::empty();
+        decode::register_builtin_decoders(&mut registry);
+        registry
+    }
+
+    /// A registry without any decoders
+    pub fn empty() -> CodecRegistry {
+        CodecRegistry { decoders: HashMap::new() }
+    }
+
+    /// Adds a decoder for the method ID, replacing the one that was registered before
+    pub fn register<D>(&mut self, method_id: &[u8], decoder: D) where D: Decoder + 'static {
+        self.decoders.insert(method_id.to_vec(), Arc::new(decoder));
+    }
+
+    pub fn get(&self, method_id: &[u8]) -> Option<&dyn Decoder> {
+        self.decoders.get(method_id).map(|decoder| decoder.as_ref())
+    }
+
+    /// The decoder for the method ID, if there is one and it reads this many input streams
+    pub fn find(&self, method_id: &[u8], num_in_streams: usize) -> Result<&dyn Decoder, ArchiveError> {
+        let decoder = self.get(method_id).ok_or_else(|| ArchiveError::new(&format!("Unrecognized compression ID {:?}", method_id)))?;
+        if decoder.num_in_streams() != num_in_streams {
+            return Err(ArchiveError::new(&format!("Compression ID {:?} can't take {} input streams", method_id, num_in_streams)));
+        }
+        Ok(decoder)
+    }
+
+    pub fn decode(&self, method_id: &[u8], inputs: &[&[u8]], properties: &[u8], unpack_size: u64, options: &DecompressOptions) -> Result<Vec<u8>, ArchiveError> {
+        self.find(method_id, inputs.len())?.decode(inputs, properties, unpack_size, options)
+    }
+}
+
+impl Default for CodecRegistry {
+    fn default() -> CodecRegistry {
+        CodecRegistry::new()
+    }
+}
+
+impl fmt::Debug for CodecRegistry {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_set().entries(self.decoders.keys()).finish()
+    }
+}
+
+mod tests {
+    #[test]
+    fn builtin_decoders() {
+        let registry = super::CodecRegistry::new();
+        assert!(registry.get(&[0x21]).is_some());
+        assert_eq!(registry.get(&[0x3, 0x3, 0x1, 0x1B]).unwrap().num_in_streams(), 4);
+        assert!(registry.get(&[0x7F]).is_none());
+    }
+
+    #[test]
+    fn wrong_number_of_inputs() {
+        let registry = super::CodecRegistry::new();
+        let result = registry.decode(&[0x0], &[b"ab", b"cd"], &[], 2, &Default::default());
+        assert!(result.is_err(), "Should return an error");
+    }
+
+    #[test]
+    #[cfg(not(target_arch = "wasm32"))]
+    fn options_are_send_and_sync() {
+        fn is_send_and_sync<T: Send + Sync>() {}
+        is_send_and_sync::<super::DecompressOptions>();
+    }
+}
@@ -0,0 +1,113 @@
+// 7-Zip's AES-256 coder (06 F1 07 01). The key is derived from the password with
+// 2^cycles_power rounds of SHA-256 over (salt, password, round counter); the data
+// is encrypted with AES-256 in CBC mode.
+use internal::{ArchiveError, ErrorKind};
+use super::aes::Aes256;
+use super::cbc::Decryptor;
+use super::cbc::cipher::{BlockDecryptMut, KeyIvInit};
+use super::cbc::cipher::block_padding::NoPadding;
+use super::sha2::{Digest, Sha256};
+
+const AES_BLOCK_SIZE: usize = 16;
+// 7-Zip itself refuses anything above this
+const MAX_CYCLES_POWER: u8 = 24;
+// Means "no hashing": the key is the salt and the password, zero padded
+const NO_HASHING_CYCLES_POWER: u8 = 0x3F;
+
+struct AesProperties {
+    cycles_power: u8,
+    salt: Vec<u8>,
+    iv: [u8; AES_BLOCK_SIZE],
+}
+
+// The first byte holds the cycles power, and the high bits of the salt and IV sizes;
+// the second byte holds the rest of the salt size (high nibble) and IV size (low nibble).
+fn read_properties(properties: &[u8]) -> Result<AesProperties, ArchiveError> {
+    let invalid = || ArchiveError::new("Invalid AES properties");
+    let first = *properties.first().ok_or_else(invalid)?;
+    let cycles_power = first & 0x3F;
+    let mut salt = Vec::new();
+    let mut iv = [0u8; AES_BLOCK_SIZE];
+    if first & 0xC0 != 0 {
+        let second = *properties.get(1).ok_or_else(invalid)?;
+        let salt_size = ((first >> 7) & 1) as usize + (second >> 4) as usize;
+        let iv_size = ((first >> 6) & 1) as usize + (second & 0x0F) as usize;
+        if properties.len() != 2 + salt_size + iv_size {
+            return Err(invalid());
+        }
+        salt.extend_from_slice(&properties[2..2 + salt_size]);
+        iv[..iv_size].copy_from_slice(&properties[2 + salt_size..]);
+    }
+    if cycles_power > MAX_CYCLES_POWER && cycles_power != NO_HASHING_CYCLES_POWER {
+        return Err(ArchiveError::new(&format!("Unsupported AES key derivation cycles power {}", cycles_power)));
+    }
+    Ok(AesProperties { cycles_power, salt, iv })
+}
+
+pub fn read_cycles_power(properties: &[u8]) -> Result<u8, ArchiveError> {
+    Ok(read_properties(properties)?.cycles_power)
+}
+
+fn derive_key(password: &str, salt: &[u8], cycles_power: u8) -> [u8; 32] {
+    // The password is hashed as UTF-16LE
+    let password: Vec<u8> = password.encode_utf16().flat_map(|c| c.to_le_bytes()).collect();
+    let mut key = [0u8; 32];
+    if cycles_power == NO_HASHING_CYCLES_POWER {
+        let material: Vec<u8> = salt.iter().chain(password.iter()).cloned().take(key.len()).collect();
+        key[..material.len()].copy_from_slice(&material);
+        return key;
+    }
+
+    let mut hasher = Sha256::new();
+    for round in 0..(1u64 << cycles_power) {
+        hasher.update(salt);
+        hasher.update(&password);
+        hasher.update(round.to_le_bytes());
+    }
+    key.copy_from_slice(&hasher.finalize());
+    key
+}
+
+pub fn decode_aes(reader: &[u8], properties: &[u8], unpack_size: u64, password: Option<&str>) -> Result<Vec<u8>, ArchiveError> {
+    let password = password.ok_or_else(|| ArchiveError::with_kind(ErrorKind::PasswordRequired, "The archive is encrypted, but no password was given"))?;
+    let properties = read_properties(properties)?;
+    if !reader.len().is_multiple_of(AES_BLOCK_SIZE) {
+        return Err(ArchiveError::new("Encrypted stream size is not a multiple of the AES block size"));
+    }
+    if (reader.len() as u64) < unpack_size {
+        return Err(ArchiveError::new("Encrypted stream is shorter than its unpack size"));
+    }
+
+    let key = derive_key(password, &properties.salt, properties.cycles_power);
+    let mut out = reader.to_vec();
+    Decryptor::<Aes256>::new(&key.into(), &properties.iv.into())
+        .decrypt_padded_mut::<NoPadding>(&mut out)
+        .map_err(|_| ArchiveError::new("AES decryption failed"))?;
+    out.truncate(unpack_size as usize);
+    Ok(out)
+}
+
+mod tests {
+    #[test]
+    fn properties_with_salt_and_iv() {
+        // Cycles power 19, no salt, 16 byte IV
+        let mut properties = vec![0x40 | 19, 0x0F];
+        properties.extend_from_slice(&[7u8; 16]);
+        let result = super::read_properties(&properties).unwrap();
+        assert_eq!(result.cycles_power, 19);
+        assert!(result.salt.is_empty());
+        assert_eq!(result.iv, [7u8; 16]);
+    }
+
+    #[test]
+    fn properties_with_wrong_size() {
+        let result = super::read_properties(&[0x40 | 19, 0x0F, 1, 2, 3]);
+        assert!(result.is_err(), "Should return an error");
+    }
+
+    #[test]
+    fn password_required() {
+        let result = super::decode_aes(&[0u8; 16], &[19], 16, None);
+        assert_eq!(result.err().unwrap().kind, super::ErrorKind::PasswordRequired);
+    }
+}
@@ -1,32 +1,421 @@
-use internal::ArchiveError;
-use std::io;
+use internal::{ArchiveError, DecompressOptions, ErrorKind};
+use internal::bcj;
+use internal::bcj2;
+use internal::crypto;
+use internal::codec::{CodecRegistry, Decoder};
+use super::byteorder::{ByteOrder, LittleEndian};
+use super::lzma_rust2;
 use std::io::Read;
 
-fn decode_lzma(reader: &[u8], properties: &[u8], unpack_size: u64) -> Result<Vec<u8>, ArchiveError> {
-    let mut cursor = properties.chain(reader);
-    let mut out = Vec::with_capacity(unpack_size as usize); // vec![0u8; unpack_size as usize];
+// The smallest dictionary LZMA decoders use
+const MIN_DICT_SIZE: u64 = 1 << 12;
 
-    let unpacked_size = lzma_rs::decompress::UnpackedSize::UseProvided(Some(unpack_size));
-    lzma_rs::lzma_decompress_with_options(&mut cursor, &mut out, &lzma_rs::decompress::Options { unpacked_size }).map_err(|e| ArchiveError::new(&format!("{:?}", e)))?;
+// Unpack sizes come from the archive, so output buffers start at most this large and grow as data is decoded
+const MAX_INITIAL_CAPACITY: u64 = 1 << 24;
 
+pub fn output_buffer(unpack_size: u64) -> Vec<u8> {
+    Vec::with_capacity(unpack_size.min(MAX_INITIAL_CAPACITY) as usize)
+}
+
+// The decoders never look further back than the data they have decoded, so the dictionary
+// doesn't need to be larger than the output. What remains has to fit in the memory limit.
+fn get_dict_size(dict_size: u64, unpack_size: u64, options: &DecompressOptions) -> Result<u32, ArchiveError> {
+    let dict_size = dict_size.min(unpack_size).max(MIN_DICT_SIZE);
+    check_memory_limit(dict_size, options)?;
+    Ok(dict_size as u32)
+}
+
+fn check_memory_limit(size: u64, options: &DecompressOptions) -> Result<(), ArchiveError> {
+    match options.memory_limit {
+        Some(limit) if size > limit => Err(ArchiveError::with_kind(ErrorKind::MemoryLimitExceeded, &format!("The decoder needs {} bytes of memory, more than the limit of {} bytes", size, limit))),
+        _ => Ok(())
+    }
+}
+
+// The properties are the lc/lp/pb byte, followed by the dictionary size (UInt32)
+pub fn read_lzma_properties(properties: &[u8]) -> Result<(u8, u32), ArchiveError> {
+    if properties.len() != 5 {
+        return Err(ArchiveError::new("Invalid LZMA properties"));
+    }
+    Ok((properties[0], LittleEndian::read_u32(&properties[1..])))
+}
+
+// The property byte encodes the dictionary size as 2 or 3 times a power of two,
+// from 4 KiB up to 3 GiB; 40 means 4 GiB - 1
+pub fn read_lzma2_dict_size(properties: &[u8]) -> Result<u64, ArchiveError> {
+    match properties {
+        [40] => Ok(0xFFFF_FFFF),
+        [bits] if *bits < 40 => Ok((2 | (*bits as u64 & 1)) << (*bits / 2 + 11)),
+        _ => Err(ArchiveError::new("Invalid LZMA2 properties"))
+    }
+}
+
+fn decode_lzma(reader: &[u8], properties: &[u8], unpack_size: u64, options: &DecompressOptions) -> Result<Vec<u8>, ArchiveError> {
+    let (props, dict_size) = read_lzma_properties(properties)?;
+    let dict_size = get_dict_size(dict_size as u64, unpack_size, options)?;
+    let decoder = lzma_rust2::LzmaReader::new_with_props(reader, unpack_size, props, dict_size, None).map_err(|e| ArchiveError::new(&format!("LZMA error: {}", e)))?;
+    read_decoded(decoder, unpack_size, "LZMA")
+}
+
+fn decode_lzma2(reader: &[u8], properties: &[u8], unpack_size: u64, options: &DecompressOptions) -> Result<Vec<u8>, ArchiveError> {
+    let dict_size = get_dict_size(read_lzma2_dict_size(properties)?, unpack_size, options)?;
+    let decoder = lzma_rust2::Lzma2Reader::new(reader, dict_size, None);
+    read_decoded(decoder, unpack_size, "LZMA2")
+}
+
+// Truncated input makes most decoders stop early rather than fail
+fn check_unpack_size(out: Vec<u8>, unpack_size: u64, name: &str) -> Result<Vec<u8>, ArchiveError> {
+    if (out.len() as u64) < unpack_size {
+        return Err(ArchiveError::new(&format!("{} stream is shorter than its unpack size", name)));
+    }
+    Ok(out)
+}
+
+fn read_unpack_size<R>(decoder: R, unpack_size: u64, name: &str) -> Result<Vec<u8>, ArchiveError> where R: Read {
+    let mut out = output_buffer(unpack_size);
+    decoder.take(unpack_size).read_to_end(&mut out).map_err(|e| ArchiveError::new(&format!("{} error: {}", name, e)))?;
+    check_unpack_size(out, unpack_size, name)
+}
+
+// The stream has to end right after the unpack size, or it is corrupt
+fn read_decoded<R>(mut decoder: R, unpack_size: u64, name: &str) -> Result<Vec<u8>, ArchiveError> where R: Read {
+    let out = read_unpack_size(decoder.by_ref(), unpack_size, name)?;
+    if decoder.read(&mut [0u8; 1]).map_err(|e| ArchiveError::new(&format!("{} error: {}", name, e)))? != 0 {
+        return Err(ArchiveError::new(&format!("{} stream is longer than its unpack size", name)));
+    }
     Ok(out)
+}
+
+fn decode_copy(reader: &[u8], unpack_size: u64) -> Result<Vec<u8>, ArchiveError> {
+    if (reader.len() as u64) < unpack_size {
+        return Err(ArchiveError::new("Stored stream is shorter than its unpack size"));
+    }
+    Ok(reader[..unpack_size as usize].to_vec())
+}
+
+fn decode_deflate(reader: &[u8], _properties: &[u8], unpack_size: u64) -> Result<Vec<u8>, ArchiveError> {
+    let out = miniz_oxide::inflate::decompress_to_vec_with_limit(reader, unpack_size as usize).map_err(|e| ArchiveError::new(&format!("Deflate error: {}", e)))?;
+    check_unpack_size(out, unpack_size, "Deflate")
+}
+
+fn decode_deflate64(reader: &[u8], _properties: &[u8], unpack_size: u64) -> Result<Vec<u8>, ArchiveError> {
+    let decoder = deflate64::Deflate64Decoder::with_buffer(reader);
+    read_decoded(decoder, unpack_size, "Deflate64")
+}
+
+// Multithreaded 7-Zip writes several concatenated bzip2 streams, which MultiBzDecoder reads back to back
+fn decode_bzip2(reader: &[u8], _properties: &[u8], unpack_size: u64) -> Result<Vec<u8>, ArchiveError> {
+    let decoder = bzip2::read::MultiBzDecoder::new(reader);
+    read_decoded(decoder, unpack_size, "BZip2")
+}
+
+// The properties are the model order (1 byte) followed by the memory size (UInt32)
+fn decode_ppmd(reader: &[u8], properties: &[u8], unpack_size: u64, options: &DecompressOptions) -> Result<Vec<u8>, ArchiveError> {
+    if properties.len() < 5 {
+        return Err(ArchiveError::new("Invalid PPMd properties"));
+    }
+    let order = properties[0] as u32;
+    let mem_size = LittleEndian::read_u32(&properties[1..5]);
+    check_memory_limit(mem_size as u64, options)?;
 
+    // The stream has no end marker, so the decoder would keep producing bytes after the unpack size
+    let decoder = ppmd_rust::Ppmd7Decoder::new(reader, order, mem_size).map_err(|e| ArchiveError::new(&format!("PPMd error: {:?}", e)))?;
+    read_unpack_size(decoder, unpack_size, "PPMd")
 }
 
-fn decode_lzma2(reader: &[u8], _properties: &[u8], unpack_size: u64) -> Result<Vec<u8>, ArchiveError> {
-    let mut cursor = io::Cursor::new(&reader);
-    let mut out = Vec::with_capacity(unpack_size as usize);
-    lzma_rs::lzma2_decompress(&mut cursor, &mut out).map_err(|e| ArchiveError::new(&format!("{:?}", e)))?;
+fn decode_bcj(reader: &[u8], unpack_size: u64, start_offset: u32, filter: fn(&mut [u8], u32)) -> Result<Vec<u8>, ArchiveError> {
+    let mut out = decode_copy(reader, unpack_size)?;
+    filter(&mut out, start_offset);
     Ok(out)
 }
 
-pub fn decode(method: &[u8], reader: &[u8], properties: &[u8], unpack_size: u64) -> Result<Vec<u8>, ArchiveError> {
-    match method {
-        [0x21] => decode_lzma2(reader, properties, unpack_size),
-        [0x3, 0x1, 0x1] => decode_lzma(reader, properties, unpack_size),
-        _ => {
-            println!("Unrecognized compression ID {:?}", method);
-            Err(ArchiveError::new(&format!("Unrecognized compression ID {:?}", method)))
+// The ARM64 and RISC-V filters take an optional start offset (UInt32), which has to be
+// a multiple of the instruction alignment
+pub fn read_start_offset(properties: &[u8], alignment: u32) -> Result<u32, ArchiveError> {
+    let start_offset = match properties.len() {
+        0 => 0,
+        4 => LittleEndian::read_u32(properties),
+        _ => return Err(ArchiveError::new("Invalid branch filter properties"))
+    };
+    if start_offset % alignment != 0 {
+        return Err(ArchiveError::new(&format!("Branch filter start offset {} is not aligned", start_offset)));
+    }
+    Ok(start_offset)
+}
+
+// The property byte is the distance minus one. Each byte was stored as the difference
+// to the byte `distance` positions before it.
+fn decode_delta(reader: &[u8], properties: &[u8], unpack_size: u64) -> Result<Vec<u8>, ArchiveError> {
+    if properties.len() != 1 {
+        return Err(ArchiveError::new("Invalid Delta properties"));
+    }
+    let distance = properties[0] as usize + 1;
+    let mut out = decode_copy(reader, unpack_size)?;
+    for i in distance..out.len() {
+        out[i] = out[i].wrapping_add(out[i - distance]);
+    }
+    Ok(out)
+}
+
+// Zstandard, LZ4 and Brotli as written by the 7-Zip ZS fork. Its multithreaded encoders emit
+// independent frames, each preceded by a skippable frame (magic 0x184D2A50..=0x184D2A5F,
+// followed by the UInt32 length of its payload).
+#[cfg(any(feature = "zstd", feature = "lz4", feature = "brotli"))]
+const SKIPPABLE_FRAME_HEADER_SIZE: usize = 8;
+
+#[cfg(any(feature = "zstd", feature = "lz4", feature = "brotli"))]
+fn read_skippable_frame(reader: &[u8]) -> Option<(&[u8], &[u8])> {
+    if reader.len() < SKIPPABLE_FRAME_HEADER_SIZE {
+        return None;
+    }
+    let magic = LittleEndian::read_u32(&reader[0..4]);
+    if magic & 0xFFFF_FFF0 != 0x184D_2A50 {
+        return None;
+    }
+    let size = LittleEndian::read_u32(&reader[4..8]) as usize;
+    let rest = &reader[SKIPPABLE_FRAME_HEADER_SIZE..];
+    if rest.len() < size {
+        return None;
+    }
+    Some((&rest[..size], &rest[size..]))
+}
+
+// ruzstd's streaming decoder reads a single frame, consuming exactly its bytes from `remaining`
+#[cfg(feature = "zstd")]
+fn decode_zstd(reader: &[u8], _properties: &[u8], unpack_size: u64) -> Result<Vec<u8>, ArchiveError> {
+    let error = |e: &dyn std::fmt::Display| ArchiveError::new(&format!("Zstd error: {}", e));
+    let mut remaining = reader;
+    let mut out = output_buffer(unpack_size);
+    while !remaining.is_empty() && (out.len() as u64) < unpack_size {
+        if let Some((_, rest)) = read_skippable_frame(remaining) {
+            remaining = rest;
+            continue;
+        }
+        let decoder = ruzstd::decoding::StreamingDecoder::new(&mut remaining).map_err(|e| error(&e))?;
+        let limit = unpack_size - out.len() as u64;
+        decoder.take(limit).read_to_end(&mut out).map_err(|e| error(&e))?;
+    }
+    check_unpack_size(out, unpack_size, "Zstd")
+}
+
+// An LZ4 frame is its header (magic number, FLG and BD bytes, optional content size and dictionary ID,
+// header checksum), followed by blocks up to an empty one and an optional content checksum. Each block
+// is its UInt32 size (the high bit marks a stored block), the data and an optional block checksum.
+#[cfg(feature = "lz4")]
+fn read_lz4_frame(reader: &[u8]) -> Result<(&[u8], &[u8]), ArchiveError> {
+    let out_of_bounds = || ArchiveError::new("LZ4 error: frame is out of bounds");
+    if reader.len() < 7 || LittleEndian::read_u32(&reader[0..4]) != 0x184D_2204 {
+        return Err(ArchiveError::new("LZ4 error: not an LZ4 frame"));
+    }
+    let flags = reader[4];
+    let mut size = 7;
+    if flags & 0x08 != 0 {
+        size += 8;
+    }
+    if flags & 0x01 != 0 {
+        size += 4;
+    }
+    let block_checksum_size = if flags & 0x10 != 0 { 4 } else { 0 };
+    loop {
+        let block_size = LittleEndian::read_u32(reader.get(size..).and_then(|r| r.get(..4)).ok_or_else(out_of_bounds)?) & 0x7FFF_FFFF;
+        size += 4;
+        if block_size == 0 {
+            break;
+        }
+        size = size.checked_add(block_size as usize + block_checksum_size).ok_or_else(out_of_bounds)?;
+    }
+    if flags & 0x04 != 0 {
+        size += 4;
+    }
+    if size > reader.len() {
+        return Err(out_of_bounds());
+    }
+    Ok(reader.split_at(size))
+}
+
+// lz4_flex's frame decoder carries on with the next frame when it is read past the end of one,
+// and fails on skippable frames, so it gets one frame at a time
+#[cfg(feature = "lz4")]
+fn decode_lz4(reader: &[u8], _properties: &[u8], unpack_size: u64) -> Result<Vec<u8>, ArchiveError> {
+    let mut remaining = reader;
+    let mut out = output_buffer(unpack_size);
+    while !remaining.is_empty() && (out.len() as u64) < unpack_size {
+        if let Some((_, rest)) = read_skippable_frame(remaining) {
+            remaining = rest;
+            continue;
         }
+        let (frame, rest) = read_lz4_frame(remaining)?;
+        remaining = rest;
+        let limit = unpack_size - out.len() as u64;
+        lz4_flex::frame::FrameDecoder::new(frame).take(limit).read_to_end(&mut out).map_err(|e| ArchiveError::new(&format!("LZ4 error: {}", e)))?;
+    }
+    check_unpack_size(out, unpack_size, "LZ4")
+}
+
+// Multithreaded Brotli frames are announced by an 8 byte skippable frame payload:
+// the compressed size (UInt32), the magic "BR" (UInt16) and a size hint (UInt16).
+// Single threaded output is a plain Brotli stream.
+#[cfg(feature = "brotli")]
+fn decode_brotli(reader: &[u8], _properties: &[u8], unpack_size: u64) -> Result<Vec<u8>, ArchiveError> {
+    let mut remaining = reader;
+    let mut out = output_buffer(unpack_size);
+    while !remaining.is_empty() && (out.len() as u64) < unpack_size {
+        let stream = match read_skippable_frame(remaining) {
+            Some((payload, rest)) => {
+                remaining = rest;
+                if payload.len() != 8 || LittleEndian::read_u16(&payload[4..6]) != 0x5242 {
+                    continue;
+                }
+                let size = LittleEndian::read_u32(&payload[0..4]) as usize;
+                if remaining.len() < size {
+                    return Err(ArchiveError::new("Brotli error: frame is out of bounds"));
+                }
+                let (stream, rest) = remaining.split_at(size);
+                remaining = rest;
+                stream
+            },
+            None => {
+                let stream = remaining;
+                remaining = &[];
+                stream
+            }
+        };
+        let limit = unpack_size - out.len() as u64;
+        brotli_decompressor::Decompressor::new(stream, 4096).take(limit).read_to_end(&mut out).map_err(|e| ArchiveError::new(&format!("Brotli error: {}", e)))?;
+    }
+    check_unpack_size(out, unpack_size, "Brotli")
+}
+
+// Most built-in decoders read a single stream, and don't need the options
+type SingleInputDecoder = fn(&[u8], &[u8], u64) -> Result<Vec<u8>, ArchiveError>;
+
+fn single_input(decode: SingleInputDecoder) -> impl Fn(&[u8], &[u8], u64, &DecompressOptions) -> Result<Vec<u8>, ArchiveError> {
+    move |reader, properties, unpack_size, _options| decode(reader, properties, unpack_size)
+}
+
+// Reads the main stream, the CALL and JMP targets, and the range coded stream
+struct Bcj2Decoder;
+
+impl Decoder for Bcj2Decoder {
+    fn num_in_streams(&self) -> usize {
+        4
+    }
+
+    fn decode(&self, inputs: &[&[u8]], _properties: &[u8], unpack_size: u64, _options: &DecompressOptions) -> Result<Vec<u8>, ArchiveError> {
+        bcj2::decode(inputs[0], inputs[1], inputs[2], inputs[3], unpack_size)
+    }
+}
+
+fn decode_aes(reader: &[u8], properties: &[u8], unpack_size: u64, options: &DecompressOptions) -> Result<Vec<u8>, ArchiveError> {
+    crypto::decode_aes(reader, properties, unpack_size, options.password.as_deref())
+}
+
+pub fn register_builtin_decoders(registry: &mut CodecRegistry) {
+    registry.register(&[0x00], single_input(|reader, _, unpack_size| decode_copy(reader, unpack_size)));
+    registry.register(&[0x21], decode_lzma2);
+    registry.register(&[0x3, 0x1, 0x1], decode_lzma);
+    registry.register(&[0x4, 0x1, 0x8], single_input(decode_deflate));
+    registry.register(&[0x4, 0x1, 0x9], single_input(decode_deflate64));
+    registry.register(&[0x3, 0x4, 0x1], decode_ppmd);
+    registry.register(&[0x4, 0x2, 0x2], single_input(decode_bzip2));
+    registry.register(&[0x3], single_input(decode_delta));
+    registry.register(&[0x3, 0x3, 0x1, 0x3], single_input(|reader, _, unpack_size| decode_bcj(reader, unpack_size, 0, bcj::x86_decode)));
+    registry.register(&[0x3, 0x3, 0x5, 0x1], single_input(|reader, _, unpack_size| decode_bcj(reader, unpack_size, 0, bcj::arm_decode)));
+    registry.register(&[0x3, 0x3, 0x7, 0x1], single_input(|reader, _, unpack_size| decode_bcj(reader, unpack_size, 0, bcj::armt_decode)));
+    registry.register(&[0x3, 0x3, 0x2, 0x5], single_input(|reader, _, unpack_size| decode_bcj(reader, unpack_size, 0, bcj::ppc_decode)));
+    registry.register(&[0x3, 0x3, 0x4, 0x1], single_input(|reader, _, unpack_size| decode_bcj(reader, unpack_size, 0, bcj::ia64_decode)));
+    registry.register(&[0x3, 0x3, 0x8, 0x5], single_input(|reader, _, unpack_size| decode_bcj(reader, unpack_size, 0, bcj::sparc_decode)));
+    registry.register(&[0xA], single_input(|reader, properties, unpack_size| decode_bcj(reader, unpack_size, read_start_offset(properties, 4)?, bcj::arm64_decode)));
+    registry.register(&[0xB], single_input(|reader, properties, unpack_size| decode_bcj(reader, unpack_size, read_start_offset(properties, 2)?, bcj::riscv_decode)));
+    registry.register(&[0x3, 0x3, 0x1, 0x1B], Bcj2Decoder);
+    registry.register(&[0x6, 0xF1, 0x7, 0x1], decode_aes);
+    #[cfg(feature = "zstd")]
+    registry.register(&[0x4, 0xF7, 0x11, 0x1], single_input(decode_zstd));
+    #[cfg(feature = "brotli")]
+    registry.register(&[0x4, 0xF7, 0x11, 0x2], single_input(decode_brotli));
+    #[cfg(feature = "lz4")]
+    registry.register(&[0x4, 0xF7, 0x11, 0x4], single_input(decode_lz4));
+}
+
+mod tests {
+    #[test]
+    fn lzma2_dict_size() {
+        assert_eq!(super::read_lzma2_dict_size(&[0]).unwrap(), 4096);
+        assert_eq!(super::read_lzma2_dict_size(&[1]).unwrap(), 6144);
+        assert_eq!(super::read_lzma2_dict_size(&[24]).unwrap(), 16 << 20);
+        assert_eq!(super::read_lzma2_dict_size(&[40]).unwrap(), 0xFFFF_FFFF);
+        assert!(super::read_lzma2_dict_size(&[41]).is_err(), "Should return an error");
+        assert!(super::read_lzma2_dict_size(&[]).is_err(), "Should return an error");
+    }
+
+    #[test]
+    fn dict_size_limited_by_unpack_size() {
+        let options = super::DecompressOptions::default();
+        assert_eq!(super::get_dict_size(0xFFFF_FFFF, 10_000, &options).unwrap(), 10_000);
+        assert_eq!(super::get_dict_size(1 << 20, 100, &options).unwrap(), 4096);
+        let result = super::get_dict_size(0xFFFF_FFFF, 1 << 40, &options);
+        assert_eq!(result.err().unwrap().kind, super::ErrorKind::MemoryLimitExceeded);
+    }
+
+    #[test]
+    fn unpack_size_is_not_allocated_up_front() {
+        let result = super::read_decoded(&b"abc"[..], 1 << 45, "Test");
+        assert_eq!(result.err().unwrap().message, "Test stream is shorter than its unpack size");
+    }
+
+    #[test]
+    fn deflate_short_stream() {
+        // A single stored block with "abc"
+        let stream = [0x01, 0x03, 0x00, 0xFC, 0xFF, b'a', b'b', b'c'];
+        assert_eq!(super::decode_deflate(&stream, &[], 3).unwrap(), b"abc");
+        let result = super::decode_deflate(&stream, &[], 4);
+        assert_eq!(result.err().unwrap().message, "Deflate stream is shorter than its unpack size");
+    }
+
+    #[test]
+    fn deflate64_short_stream() {
+        // A single stored block with "abc"
+        let stream = [0x01, 0x03, 0x00, 0xFC, 0xFF, b'a', b'b', b'c'];
+        assert_eq!(super::decode_deflate64(&stream, &[], 3).unwrap(), b"abc");
+        let result = super::decode_deflate64(&stream, &[], 4);
+        assert_eq!(result.err().unwrap().message, "Deflate64 stream is shorter than its unpack size");
+    }
+
+    #[test]
+    fn bzip2_short_stream() {
+        // "abc", compressed with Python's bz2 module
+        let stream = [
+            0x42, 0x5A, 0x68, 0x39, 0x31, 0x41, 0x59, 0x26, 0x53, 0x59, 0x64, 0x8C, 0xBB, 0x73, 0x00, 0x00, 0x00, 0x01, 0x00,
+            0x38, 0x00, 0x20, 0x00, 0x21, 0x98, 0x19, 0x84, 0x61, 0x77, 0x24, 0x53, 0x85, 0x09, 0x06, 0x48, 0xCB, 0xB7, 0x30
+        ];
+        assert_eq!(super::decode_bzip2(&stream, &[], 3).unwrap(), b"abc");
+        let result = super::decode_bzip2(&stream, &[], 4);
+        assert_eq!(result.err().unwrap().message, "BZip2 stream is shorter than its unpack size");
+    }
+
+    #[test]
+    #[cfg(feature = "lz4")]
+    fn lz4_frames() {
+        // "abc" with the lz4 command line tool, then again with a content checksum, after a skippable frame
+        let stream = [
+            0x04, 0x22, 0x4D, 0x18, 0x60, 0x40, 0x82, 0x03, 0x00, 0x00, 0x80, 0x61, 0x62, 0x63, 0x00, 0x00, 0x00, 0x00,
+            0x50, 0x2A, 0x4D, 0x18, 0x02, 0x00, 0x00, 0x00, 0x00, 0x00,
+            0x04, 0x22, 0x4D, 0x18, 0x64, 0x40, 0xA7, 0x03, 0x00, 0x00, 0x80, 0x61, 0x62, 0x63, 0x00, 0x00, 0x00, 0x00,
+            0xFF, 0x53, 0xD1, 0x32
+        ];
+        assert_eq!(super::decode_lz4(&stream, &[], 6).unwrap(), b"abcabc");
+        let result = super::decode_lz4(&stream, &[], 7);
+        assert_eq!(result.err().unwrap().message, "LZ4 stream is shorter than its unpack size");
+        assert!(super::decode_lz4(&stream[..16], &[], 3).is_err(), "Should return an error");
+    }
+
+    #[test]
+    #[cfg(feature = "brotli")]
+    fn brotli_short_stream() {
+        // "bc", compressed with the brotli crate
+        let stream = [0x8B, 0x00, 0x80, 0x62, 0x63, 0x03];
+        assert_eq!(super::decode_brotli(&stream, &[], 2).unwrap(), b"bc");
+        let result = super::decode_brotli(&stream, &[], 3);
+        assert_eq!(result.err().unwrap().message, "Brotli stream is shorter than its unpack size");
     }
 }
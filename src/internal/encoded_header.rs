@@ -1,12 +1,11 @@
-use internal::{ArchiveError, SIGNATURE_HEADER_SIZE};
+use internal::{check_password, decode_main_coder, get_pack_stream, ArchiveError, DecompressOptions, ErrorKind};
+use internal::methods;
 use internal::nid::NID;
 use internal::nid::read_nid;
 use internal::read_utils;
 use internal::read_utils::read_dyn_uint64 as dyn64;
-use internal::decode;
 use std::vec::Vec;
 use std::io;
-use std::io::prelude::*;
 use super::byteorder::ReadBytesExt;
 
 #[derive(Debug)]
@@ -20,7 +19,7 @@ fn or_archive_error<R>(result: Result<R, io::Error>) -> Result<R, ArchiveError>
     result.map_err(|e| ArchiveError::new(&e.to_string()))
 }
 
-pub fn read_streams_info<R>(buf: &mut R) -> Result<StreamsInfo, ArchiveError> where R: io::BufRead {
+pub fn read_streams_info<R>(buf: &mut R, additional_streams: &[Vec<u8>]) -> Result<StreamsInfo, ArchiveError> where R: io::BufRead {
     let mut nid = read_nid(buf)?;
 
     let mut pack_info: Option<PackInfo> = None;
@@ -33,7 +32,7 @@ pub fn read_streams_info<R>(buf: &mut R) -> Result<StreamsInfo, ArchiveError> wh
 
     let mut folders: Vec<Folder> = Vec::new();
     if nid == NID::UnpackInfo {
-        folders = read_unpack_info(buf)?;
+        folders = read_unpack_info(buf, additional_streams)?;
         nid = read_nid(buf)?;
     }
 
@@ -48,7 +47,7 @@ pub fn read_streams_info<R>(buf: &mut R) -> Result<StreamsInfo, ArchiveError> wh
 
     Ok(StreamsInfo {
         pack_info: pack_info.unwrap(),
-        substreams_info: substreams_info,
+        substreams_info,
         folders
     })
 }
@@ -60,15 +59,20 @@ pub struct PackInfo {
     pack_crcs_defined: bit_set::BitSet,
     pack_crcs: Vec<u32>,
 }
+impl PackInfo {
+    pub fn check_crc(&self, pack_stream_index: usize, data: &[u8]) -> bool {
+        !self.pack_crcs_defined.contains(pack_stream_index) || crc32fast::hash(data) == self.pack_crcs[pack_stream_index]
+    }
+}
 fn read_pack_info<R>(buf: &mut R) -> Result<PackInfo, ArchiveError> where R: io::BufRead {
-    let pack_pos = dyn64(buf);
-    let num_pack_streams = dyn64(buf);
+    let pack_pos = dyn64(buf)?;
+    let num_pack_streams = dyn64(buf)?;
     let mut nid = read_nid(buf)?;
 
     let mut pack_sizes: Vec<u64> = Vec::with_capacity(num_pack_streams as usize);
     if nid == NID::Size {
         for _ in 0..num_pack_streams {
-            pack_sizes.push(dyn64(buf));
+            pack_sizes.push(dyn64(buf)?);
         }
         nid = read_nid(buf)?;
     }
@@ -76,10 +80,14 @@ fn read_pack_info<R>(buf: &mut R) -> Result<PackInfo, ArchiveError> where R: io:
     let mut pack_crcs_defined = bit_set::BitSet::new();
     let mut pack_crcs: Vec<u32> = Vec::new();
     if nid == NID::Crc {
-        pack_crcs_defined = read_utils::read_all_or_bits(buf, num_pack_streams as usize);
-        pack_crcs = (0..num_pack_streams)
-            .map(|_| read_utils::read_uint32(buf))
-            .collect();
+        pack_crcs_defined = read_utils::read_all_or_bits(buf, num_pack_streams as usize)?;
+        for i in 0..(num_pack_streams as usize) {
+            pack_crcs.push(if pack_crcs_defined.contains(i) {
+                read_utils::read_uint32(buf)?
+            } else {
+                0
+            });
+        }
         nid = read_nid(buf)?;
     }
 
@@ -95,41 +103,36 @@ fn read_pack_info<R>(buf: &mut R) -> Result<PackInfo, ArchiveError> where R: io:
     })
 }
 
-fn read_unpack_info<R>(buf: &mut R) -> Result<Vec<Folder>, ArchiveError> where R: io::BufRead {
+fn read_unpack_info<R>(buf: &mut R, additional_streams: &[Vec<u8>]) -> Result<Vec<Folder>, ArchiveError> where R: io::BufRead {
     let mut nid = read_nid(buf)?;
     if nid != NID::Folder {
         return Err(ArchiveError::new(&format!("Expected NID Folder, got {:?}", nid)));
     }
-    let num_folders = dyn64(buf);
-    let external = buf.read_u8().unwrap();
-    if external != 0 {
-        return Err(ArchiveError::new("External unsupported"));
-    }
-
-    let mut folders: Vec<Folder> = Vec::with_capacity(num_folders as usize);
-    for _ in 0..num_folders {
-        folders.push(read_folder(buf)?);
-    }
+    let num_folders = dyn64(buf)?;
+    let mut folders = match read_utils::read_external(buf, additional_streams)? {
+        Some(mut external) => read_folders(&mut external, num_folders)?,
+        None => read_folders(buf, num_folders)?
+    };
 
     nid = read_nid(buf)?;
     if nid != NID::CodersUnpackSize {
         return Err(ArchiveError::new(&format!("Expected NID CodersUnpackSize, got {:?}", nid)));
     }
 
-    for mut folder in folders.iter_mut() {
+    for folder in folders.iter_mut() {
         folder.unpack_sizes = (0..folder.total_output_streams)
             .map(|_| dyn64(buf))
-            .collect();
+            .collect::<Result<_, _>>()?;
     }
 
     nid = read_nid(buf)?;
 
     if nid == NID::Crc {
-        let crcs_defined = read_utils::read_all_or_bits(buf, num_folders as usize);
+        let crcs_defined = read_utils::read_all_or_bits(buf, num_folders as usize)?;
         for i in 0..num_folders {
             if crcs_defined.contains(i as usize) {
                 folders[i as usize].has_crc = true;
-                folders[i as usize].crc = read_utils::read_uint32(buf);
+                folders[i as usize].crc = read_utils::read_uint32(buf)?;
             } else {
                 folders[i as usize].has_crc = false;
             }
@@ -146,9 +149,10 @@ fn read_unpack_info<R>(buf: &mut R) -> Result<Vec<Folder>, ArchiveError> where R
 
 #[derive(Debug)]
 pub struct SubstreamsInfo {
-    pub unpack_sizes: Vec<u64>
+    pub unpack_sizes: Vec<u64>,
+    pub crcs: Vec<Option<u32>>
 }
-fn read_substreams_info<R>(buf: &mut R, folders: &mut Vec<Folder>) -> Result<SubstreamsInfo, ArchiveError> where R: io::BufRead {
+fn read_substreams_info<R>(buf: &mut R, folders: &mut [Folder]) -> Result<SubstreamsInfo, ArchiveError> where R: io::BufRead {
     for folder in folders.iter_mut() {
         folder.num_unpack_substreams = 1;
     }
@@ -156,7 +160,7 @@ fn read_substreams_info<R>(buf: &mut R, folders: &mut Vec<Folder>) -> Result<Sub
     let mut nid = read_nid(buf)?;
     if nid == NID::NumUnpackStream {
         for folder in folders.iter_mut() {
-            folder.num_unpack_substreams = dyn64(buf);
+            folder.num_unpack_substreams = dyn64(buf)?;
         }
         nid = read_nid(buf)?;
     }
@@ -164,8 +168,6 @@ fn read_substreams_info<R>(buf: &mut R, folders: &mut Vec<Folder>) -> Result<Sub
     let total_unpack_streams: u64 = folders.iter().map(|f| f.num_unpack_substreams).sum();
 
     let mut unpack_sizes: Vec<u64> = Vec::with_capacity(folders.len());
-    let /* mut */ _has_crc = bit_set::BitSet::with_capacity(total_unpack_streams as usize);
-    let /* mut */ _crcs: Vec<i32> = Vec::with_capacity(total_unpack_streams as usize);
 
     for folder in folders.iter_mut() {
         if folder.num_unpack_substreams == 0 {
@@ -174,7 +176,7 @@ fn read_substreams_info<R>(buf: &mut R, folders: &mut Vec<Folder>) -> Result<Sub
         let mut sum = 0;
         if nid == NID::Size {
             for _ in 0..(folder.num_unpack_substreams - 1) {
-                let size = dyn64(buf);
+                let size = dyn64(buf)?;
                 unpack_sizes.push(size);
                 sum += size;
             }
@@ -187,37 +189,47 @@ fn read_substreams_info<R>(buf: &mut R, folders: &mut Vec<Folder>) -> Result<Sub
     }
 
     let mut num_digests = 0;
-    for folder in folders {
+    for folder in folders.iter() {
         if folder.num_unpack_substreams != 1 || !folder.has_crc {
             num_digests += folder.num_unpack_substreams;
         }
     }
 
+    let mut missing_crcs: Vec<Option<u32>> = Vec::new();
     if nid == NID::Crc {
-        let has_missing_crc = read_utils::read_all_or_bits(buf, num_digests as usize);
-        let mut missing_crcs: Vec<u32> = Vec::new();
+        let has_missing_crc = read_utils::read_all_or_bits(buf, num_digests as usize)?;
         for i in 0..num_digests {
             if has_missing_crc.contains(i as usize) {
-                missing_crcs.push(0xffffFFFF & read_utils::read_uint32(buf));
+                missing_crcs.push(Some(read_utils::read_uint32(buf)?));
             } else {
-                missing_crcs.push(0);
+                missing_crcs.push(None);
             }
         }
-        // TODO: implement the rest of the bookkeeping here...
-        /*
-        let mut next_crc = 0;
-        let mut next_missing_crc = 0;
-        */
 
         nid = read_nid(buf)?;
     }
 
+    // A folder with a single substream doesn't repeat its CRC here; the substream inherits the folder's CRC
+    let mut crcs: Vec<Option<u32>> = Vec::with_capacity(total_unpack_streams as usize);
+    let mut next_missing_crc = 0;
+    for folder in folders.iter() {
+        if folder.num_unpack_substreams == 1 && folder.has_crc {
+            crcs.push(Some(folder.crc));
+        } else {
+            for _ in 0..folder.num_unpack_substreams {
+                crcs.push(missing_crcs.get(next_missing_crc).cloned().unwrap_or(None));
+                next_missing_crc += 1;
+            }
+        }
+    }
+
     if nid != NID::End {
         return Err(ArchiveError::new(&format!("Badly terminated SubStreamsInfo ({:?})", nid)));
     }
 
     Ok(SubstreamsInfo {
-        unpack_sizes
+        unpack_sizes,
+        crcs
     })
 }
 
@@ -229,43 +241,175 @@ pub struct Folder {
     total_output_streams: u64,
     pub packed_streams: Vec<u64>,
     pub unpack_sizes: Vec<u64>,
-    has_crc: bool,
-    crc: u32,
+    pub has_crc: bool,
+    pub crc: u32,
 
     pub(crate) num_unpack_substreams: u64,
 }
 
 impl Folder {
     fn get_unpack_size(&self) -> u64 {
-        if self.total_output_streams == 0 {
-            return 0;
+        match self.get_main_coder() {
+            Ok(main_coder) => self.unpack_sizes.get(self.get_out_stream_index(main_coder)).cloned().unwrap_or(0),
+            Err(_) => 0
         }
-        for i in (0..self.total_output_streams).rev() {
-            if find_bind_pair_for_out_stream(&self.bind_pairs, i).is_none() {
-                return self.unpack_sizes[i as usize];
+    }
+
+    pub fn check_crc(&self, data: &[u8]) -> bool {
+        !self.has_crc || crc32fast::hash(data) == self.crc
+    }
+
+    // The coder whose output stream isn't bound to another coder's input
+    pub fn get_main_coder(&self) -> Result<usize, ArchiveError> {
+        let mut out_index = 0;
+        for (i, coder) in self.coders.iter().enumerate() {
+            for _ in 0..coder.num_out_streams {
+                if find_bind_pair_for_out_stream(&self.bind_pairs, out_index).is_none() {
+                    return Ok(i);
+                }
+                out_index += 1;
             }
         }
-        return 0;
-    }
-
-    pub fn get_ordered_coders(&self) -> Vec<&Coder> {
-        let mut coders: Vec<&Coder> = Vec::new();
-        let mut current = Some(self.packed_streams[0]);
-        loop {
-            match current {
-                Some(curr) => {
-                    coders.push(&self.coders[curr as usize]);
-                    let pair = find_bind_pair_for_out_stream(&self.bind_pairs, curr);
-                    current = pair.map(|p| self.bind_pairs[p].in_index);
-                },
-                None => {
-                    return coders;
+        Err(ArchiveError::new("Folder has no unbound output stream"))
+    }
+
+    // The stream indices are numbered across all coders, in coder order
+    pub fn get_out_stream_index(&self, coder_index: usize) -> usize {
+        self.coders[..coder_index].iter().map(|c| c.num_out_streams as usize).sum()
+    }
+
+    fn find_coder_for_out_stream(&self, out_index: u64) -> Option<usize> {
+        let mut first = 0;
+        for (i, coder) in self.coders.iter().enumerate() {
+            if out_index < first + coder.num_out_streams {
+                return Some(i);
+            }
+            first += coder.num_out_streams;
+        }
+        None
+    }
+
+    fn find_coder_for_in_stream(&self, in_index: u64) -> Option<usize> {
+        let mut first = 0;
+        for (i, coder) in self.coders.iter().enumerate() {
+            if in_index < first + coder.num_in_streams {
+                return Some(i);
+            }
+            first += coder.num_in_streams;
+        }
+        None
+    }
+
+    // Checks that the coders form a tree: every stream is bound or packed at most once,
+    // and following the bind pairs from any coder leads to the main coder without looping.
+    fn check_coder_graph(&self) -> Result<(), ArchiveError> {
+        let invalid = |message: String| ArchiveError::with_kind(ErrorKind::HeaderCorrupt, &format!("Invalid coder graph: {}", message));
+        for coder in &self.coders {
+            if coder.num_out_streams != 1 {
+                return Err(ArchiveError::new(&format!("Coders with {} output streams are unsupported", coder.num_out_streams)));
+            }
+        }
+        let mut in_streams = vec![false; self.total_input_streams as usize];
+        let mut out_streams = vec![false; self.total_output_streams as usize];
+        for pair in &self.bind_pairs {
+            if pair.in_index >= self.total_input_streams || pair.out_index >= self.total_output_streams {
+                return Err(invalid(format!("bind pair {} -> {} is out of range", pair.out_index, pair.in_index)));
+            }
+            if in_streams[pair.in_index as usize] || out_streams[pair.out_index as usize] {
+                return Err(invalid(format!("bind pair {} -> {} reuses a stream", pair.out_index, pair.in_index)));
+            }
+            in_streams[pair.in_index as usize] = true;
+            out_streams[pair.out_index as usize] = true;
+        }
+        for &packed in &self.packed_streams {
+            if packed >= self.total_input_streams || in_streams[packed as usize] {
+                return Err(invalid(format!("packed stream {} is out of range or already bound", packed)));
+            }
+            in_streams[packed as usize] = true;
+        }
+
+        let main_coder = self.get_main_coder()?;
+        for start in 0..self.coders.len() {
+            let mut coder_index = start;
+            let mut steps = 0;
+            while coder_index != main_coder {
+                steps += 1;
+                if steps > self.coders.len() {
+                    return Err(invalid(format!("coder {} is bound in a cycle", start)));
                 }
+                let out_index = self.get_out_stream_index(coder_index) as u64;
+                let pair = find_bind_pair_for_out_stream(&self.bind_pairs, out_index)
+                    .ok_or_else(|| invalid(format!("coder {} has an unbound output", coder_index)))?;
+                coder_index = self.find_coder_for_in_stream(self.bind_pairs[pair].in_index)
+                    .ok_or_else(|| invalid(format!("no coder has input stream {}", self.bind_pairs[pair].in_index)))?;
+            }
+        }
+        Ok(())
+    }
+
+    // Where each input of the coder comes from: a packed stream, or the output of another coder
+    pub fn get_in_streams(&self, coder_index: usize) -> Result<Vec<InStream>, ArchiveError> {
+        let first: u64 = self.coders[..coder_index].iter().map(|c| c.num_in_streams).sum();
+        (first..first + self.coders[coder_index].num_in_streams).map(|in_index| {
+            if let Some(pair) = find_bind_pair_for_in_stream(&self.bind_pairs, in_index) {
+                let out_index = self.bind_pairs[pair].out_index;
+                return self.find_coder_for_out_stream(out_index)
+                    .map(InStream::Coder)
+                    .ok_or_else(|| ArchiveError::new(&format!("Bind pair refers to a missing output stream {}", out_index)));
             }
+            self.packed_streams.iter().position(|&p| p == in_index)
+                .map(InStream::Packed)
+                .ok_or_else(|| ArchiveError::new(&format!("Input stream {} is neither bound nor packed", in_index)))
+        }).collect()
+    }
+
+    pub fn is_encrypted(&self) -> bool {
+        self.coders.iter().any(Coder::is_aes)
+    }
+
+    // Whether the coder reads the output of 7zAES, directly or through other coders
+    pub fn reads_decrypted_data(&self, coder_index: usize) -> bool {
+        self.get_in_streams(coder_index).unwrap_or_default().into_iter().any(|in_stream| match in_stream {
+            InStream::Coder(c) => self.coders[c].is_aes() || self.reads_decrypted_data(c),
+            InStream::Packed(_) => false
+        })
+    }
+
+    pub fn num_coders(&self) -> usize {
+        self.coders.len()
+    }
+
+    pub fn get_coder(&self, coder_index: usize) -> &Coder {
+        &self.coders[coder_index]
+    }
+
+    // The coders in decoding order, each one after the coders that feed it, e.g. "LZMA2:24 BCJ"
+    pub fn describe(&self) -> String {
+        let mut coders = Vec::with_capacity(self.coders.len());
+        if let Ok(main_coder) = self.get_main_coder() {
+            self.describe_coder(main_coder, &mut coders);
         }
+        coders.join(" ")
+    }
+
+    fn describe_coder(&self, coder_index: usize, coders: &mut Vec<String>) {
+        for in_stream in self.get_in_streams(coder_index).unwrap_or_default() {
+            if let InStream::Coder(c) = in_stream {
+                self.describe_coder(c, coders);
+            }
+        }
+        let options = &self.coders[coder_index].coder_options;
+        coders.push(methods::describe_coder(&options.decompression_method_id, &options.properties));
     }
 }
 
+pub enum InStream {
+    /// The n-th packed stream of the folder
+    Packed(usize),
+    /// The output of the coder with this index
+    Coder(usize),
+}
+
 #[derive(Debug)]
 pub struct CoderOptions {
     pub decompression_method_id: Vec<u8>,
@@ -279,34 +423,38 @@ pub struct Coder {
     num_out_streams: u64
 }
 
+impl Coder {
+    fn is_aes(&self) -> bool {
+        self.coder_options.decompression_method_id == [0x6, 0xF1, 0x7, 0x1]
+    }
+}
+
 #[derive(Debug)]
 struct BindPair {
     in_index: u64,
     out_index: u64
 }
-fn find_bind_pair_for_in_stream(bind_pairs: &Vec<BindPair>, index: u64) -> Option<usize> {
-    for i in 0..bind_pairs.len() {
-        if bind_pairs[i].in_index == index {
-            return Some(i);
-        }
-    }
-    return None;
+fn find_bind_pair_for_in_stream(bind_pairs: &[BindPair], index: u64) -> Option<usize> {
+    bind_pairs.iter().position(|pair| pair.in_index == index)
 }
-fn find_bind_pair_for_out_stream(bind_pairs: &Vec<BindPair>, index: u64) -> Option<usize> {
-    for i in 0..bind_pairs.len() {
-        if bind_pairs[i].out_index == index {
-            return Some(i);
-        }
+fn find_bind_pair_for_out_stream(bind_pairs: &[BindPair], index: u64) -> Option<usize> {
+    bind_pairs.iter().position(|pair| pair.out_index == index)
+}
+
+fn read_folders<R>(buf: &mut R, num_folders: u64) -> Result<Vec<Folder>, ArchiveError> where R: io::BufRead {
+    let mut folders: Vec<Folder> = Vec::with_capacity(num_folders as usize);
+    for _ in 0..num_folders {
+        folders.push(read_folder(buf)?);
     }
-    return None;
+    Ok(folders)
 }
 
 fn read_folder<R>(buf: &mut R) -> Result<Folder, ArchiveError> where R: io::BufRead {
-    let num_coders = dyn64(buf) as usize;
+    let num_coders = dyn64(buf)? as usize;
 
     let mut coders: Vec<Coder> = Vec::with_capacity(num_coders);
     for _ in 0..num_coders {
-        let bits = buf.read_u8().unwrap();
+        let bits = or_archive_error(buf.read_u8())?;
         let id_size = bits & 0xf;
         let is_simple = (bits & 0x10) == 0;
         let has_attributes = (bits & 0x20) != 0;
@@ -318,19 +466,19 @@ fn read_folder<R>(buf: &mut R) -> Result<Folder, ArchiveError> where R: io::BufR
         let num_in_streams = if is_simple {
             1
         } else {
-            dyn64(buf)
+            dyn64(buf)?
         };
 
         let num_out_streams = if is_simple {
             1
         } else {
-            dyn64(buf)
+            dyn64(buf)?
         };
 
         let mut properties: Vec<u8> = Vec::new();
 
         if has_attributes {
-            let properties_size = dyn64(buf);
+            let properties_size = dyn64(buf)?;
             properties = vec![0; properties_size as usize];
             or_archive_error(buf.read_exact(&mut properties))?;
             // properties = buf.read_multi(properties_size as usize);
@@ -361,8 +509,8 @@ fn read_folder<R>(buf: &mut R) -> Result<Folder, ArchiveError> where R: io::BufR
     let mut bind_pairs = Vec::with_capacity(num_bind_pairs as usize);
     for _ in 0..num_bind_pairs {
         bind_pairs.push(BindPair {
-            in_index: dyn64(buf),
-            out_index: dyn64(buf)
+            in_index: dyn64(buf)?,
+            out_index: dyn64(buf)?
         });
     }
 
@@ -386,11 +534,11 @@ fn read_folder<R>(buf: &mut R) -> Result<Folder, ArchiveError> where R: io::BufR
         packed_streams.push(idx);
     } else {
         for _ in 0..num_packed_streams {
-            packed_streams.push(dyn64(buf));
+            packed_streams.push(dyn64(buf)?);
         }
     }
 
-    Ok(Folder {
+    let folder = Folder {
         coders,
         bind_pairs,
         total_input_streams,
@@ -401,28 +549,82 @@ fn read_folder<R>(buf: &mut R) -> Result<Folder, ArchiveError> where R: io::BufR
         crc: 0,
 
         num_unpack_substreams: 0,
-    })
+    };
+    folder.check_coder_graph()?;
+    Ok(folder)
 }
 
-pub fn read_encoded_header(buf: &mut io::Cursor<&[u8]>) -> Result<Vec<u8>, ArchiveError> {
-    let info = read_streams_info(buf)?;
-    let folder = &info.folders[0];
-    let folder_offset = SIGNATURE_HEADER_SIZE + info.pack_info.pack_pos;
-
-    let unpack_size = folder.get_unpack_size();
-
-    let coders = folder.get_ordered_coders();
-
-    // just a little hack/shortcut; use the first coder
-    let coder = coders[0];
+// Decodes every folder of streams that are described in the header itself, like the encoded header
+// or the additional streams. Their packed streams follow each other, starting at pack_pos.
+pub fn decode_folders(info: &StreamsInfo, data: &[u8], options: &DecompressOptions, what: &str) -> Result<Vec<Vec<u8>>, ArchiveError> {
+    let mut decoded_folders = Vec::with_capacity(info.folders.len());
+    let mut pack_stream_index = 0;
+    let mut offset = 0;
+    for folder in &info.folders {
+        let mut packed: Vec<&[u8]> = Vec::with_capacity(folder.packed_streams.len());
+        for _ in 0..folder.packed_streams.len() {
+            let size = *info.pack_info.pack_sizes.get(pack_stream_index).ok_or_else(|| ArchiveError::new(&format!("Missing pack size for the {}", what)))?;
+            let stream = get_pack_stream(data, info.pack_info.pack_pos, offset, size)?;
+            if options.verify_pack_crcs && !info.pack_info.check_crc(pack_stream_index, stream) {
+                return Err(ArchiveError::with_kind(ErrorKind::PackCrcMismatch, &format!("CRC mismatch in the {}'s packed stream", what)));
+            }
+            packed.push(stream);
+            offset += size;
+            pack_stream_index += 1;
+        }
 
-    buf.set_position(folder_offset);
+        // With -mhe, the header itself is encrypted
+        let encrypted = folder.is_encrypted();
+        let decoded = decode_main_coder(folder, &packed, options)?;
+        if !folder.check_crc(&decoded) {
+            let error = ArchiveError::with_kind(ErrorKind::FolderCrcMismatch, &format!("CRC mismatch in the {} folder", what));
+            return Err(check_password(encrypted, error));
+        }
+        decoded_folders.push(decoded);
+    }
+    Ok(decoded_folders)
+}
 
-    let mut out = &mut vec![0u8; info.pack_info.pack_sizes[0] as usize];
-    buf.read_exact(&mut out).map_err(|e| ArchiveError::new(&e.to_string()))?;
-    decode::decode(&coder.coder_options.decompression_method_id, out, &coder.coder_options.properties, unpack_size)
+pub fn read_encoded_header<R>(buf: &mut R, data: &[u8], options: &DecompressOptions) -> Result<Vec<u8>, ArchiveError> where R: io::BufRead {
+    let info = read_streams_info(buf, &[])?;
+    if info.folders.is_empty() {
+        return Err(ArchiveError::new("The encoded header has no folder"));
+    }
+    let mut decoded_folders = decode_folders(&info, data, options, "encoded header")?;
+    Ok(decoded_folders.swap_remove(0))
 }
 
 mod tests {
+    #[test]
+    fn main_coder_listed_last() {
+        // Two coders that copy their input; coder 0 feeds coder 1 through the bind pair (in 1 <- out 0)
+        let bytes = [2u8, 0x01, 0x00, 0x01, 0x00, 1, 0];
+        let folder = super::read_folder(&mut &bytes[..]).unwrap();
+        assert_eq!(folder.get_main_coder().unwrap(), 1);
+        assert_eq!(folder.packed_streams, vec![0]);
+        assert!(matches!(folder.get_in_streams(1).unwrap()[..], [super::InStream::Coder(0)]));
+        assert!(matches!(folder.get_in_streams(0).unwrap()[..], [super::InStream::Packed(0)]));
+    }
 
+    #[test]
+    fn coders_bound_in_a_cycle() {
+        // Three copy coders; coders 1 and 2 feed each other, and never reach the main coder
+        let bytes = [3u8, 0x01, 0x00, 0x01, 0x00, 0x01, 0x00, 1, 2, 2, 1];
+        let result = super::read_folder(&mut &bytes[..]);
+        assert_eq!(result.err().unwrap().kind, super::ErrorKind::HeaderCorrupt);
+    }
+
+    #[test]
+    fn input_stream_bound_twice() {
+        let bytes = [3u8, 0x01, 0x00, 0x01, 0x00, 0x01, 0x00, 0, 1, 0, 2];
+        let result = super::read_folder(&mut &bytes[..]);
+        assert_eq!(result.err().unwrap().kind, super::ErrorKind::HeaderCorrupt);
+    }
+
+    #[test]
+    fn bind_pair_out_of_range() {
+        let bytes = [2u8, 0x01, 0x00, 0x01, 0x00, 1, 5];
+        let result = super::read_folder(&mut &bytes[..]);
+        assert_eq!(result.err().unwrap().kind, super::ErrorKind::HeaderCorrupt);
+    }
 }
@@ -1,14 +1,14 @@
-use internal::{ArchiveError};
+use internal::{ArchiveError, DecompressOptions, ErrorKind};
 use internal::nid::NID;
 use internal::nid::read_nid;
 use internal::read_utils::read_dyn_uint64 as dyn64;
 use internal::encoded_header;
 use internal::read_utils;
 use std::io;
+use std::io::Read;
 use std::string::FromUtf16Error;
 use internal::encoded_header::StreamsInfo;
 use internal::encoded_header::SubstreamsInfo;
-use super::byteorder::ReadBytesExt;
 
 fn or_archive_error<R>(result: Result<R, io::Error>) -> Result<R, ArchiveError> {
     result.map_err(|e| ArchiveError::new(&e.to_string()))
@@ -27,33 +27,35 @@ fn skip<R>(buf: &mut R, length: usize) -> Result<(), ArchiveError> where R: io::
     or_archive_error(buf.read_exact(&mut ignore))
 }
 
-pub fn read_header<R>(buf: &mut R) -> Result<Header, ArchiveError> where R: io::BufRead {
+pub fn read_header<R>(buf: &mut R, data: &[u8], options: &DecompressOptions) -> Result<Header, ArchiveError> where R: io::BufRead {
     let mut nid = read_nid(buf)?;
 
     let mut files_info: Vec<File> = Vec::new();
     let mut streams_info: Option<StreamsInfo> = None;
+    let mut additional_streams: Vec<Vec<u8>> = Vec::new();
 
     if nid == NID::ArchiveProperties {
         skip_archive_properties(buf)?;
         nid = read_nid(buf)?;
     }
 
+    // Header data flagged as external (like the file names) is stored in these streams
     if nid == NID::AdditionalStreamsInfo {
-        return Err(ArchiveError::new("Additional streams unsupported"));
+        let info = encoded_header::read_streams_info(buf, &[])?;
+        additional_streams = encoded_header::decode_folders(&info, data, options, "additional stream")?;
+        nid = read_nid(buf)?;
     }
 
     if nid == NID::MainStreamsInfo {
-        streams_info = Some(encoded_header::read_streams_info(buf)?);
+        streams_info = Some(encoded_header::read_streams_info(buf, &additional_streams)?);
         nid = read_nid(buf)?;
     }
 
     if nid == NID::FilesInfo {
-        let substreams_info = match streams_info.as_ref() {
-            Some(info) => info.substreams_info.as_ref().unwrap(),
-            None => return Err(ArchiveError::new("Missing substreams info"))
-        };
+        let substreams_info = streams_info.as_ref().and_then(|info| info.substreams_info.as_ref())
+            .ok_or_else(|| ArchiveError::with_kind(ErrorKind::HeaderCorrupt, "Missing substreams info"))?;
 
-        files_info = read_files_info(buf, &substreams_info)?;
+        files_info = read_files_info(buf, substreams_info, &additional_streams)?;
         nid = read_nid(buf)?;
     }
 
@@ -78,8 +80,26 @@ pub struct Entry {
     pub name: String,
     pub offset: u64,
     pub size: u64,
+    pub crc: Option<u32>,
     pub folder_index: usize,
 }
+impl Entry {
+    pub fn read<'a>(&self, folder_data: &'a [u8]) -> Result<&'a [u8], ArchiveError> {
+        let end = self.offset + self.size;
+        if end > folder_data.len() as u64 {
+            return Err(ArchiveError::new(&format!("{} is out of bounds of its folder", self.name)));
+        }
+        Ok(&folder_data[self.offset as usize..end as usize])
+    }
+
+    pub fn check_crc(&self, data: &[u8]) -> bool {
+        match self.crc {
+            Some(crc) => crc32fast::hash(data) == crc,
+            None => true
+        }
+    }
+}
+
 pub fn get_stream_offsets(header: &Header) -> Vec<Entry> {
     let mut offsets = Vec::new();
     let mut offsets_by_folder = vec![0; header.streams_info.folders.len()];
@@ -101,6 +121,7 @@ pub fn get_stream_offsets(header: &Header) -> Vec<Entry> {
                     name: file.name.to_string(),
                     offset,
                     size: uncompressed_size,
+                    crc: file.crc,
                     folder_index,
                 };
                 offsets.push(entry);
@@ -114,11 +135,12 @@ pub fn get_stream_offsets(header: &Header) -> Vec<Entry> {
 #[derive(Debug)]
 pub struct StreamMap {
     pub folder_first_pack_stream_index: Vec<usize>,
-    pub pack_stream_offsets: Vec<usize>,
+    pub pack_stream_offsets: Vec<u64>,
+    #[allow(dead_code)]
     folder_first_file_index: Vec<Option<usize>>,
     pub file_folder_index: Vec<Option<usize>>,
 }
-fn calculate_stream_map(files: &Vec<File>, streams_info: &StreamsInfo) -> Result<StreamMap, ArchiveError> {
+fn calculate_stream_map(files: &[File], streams_info: &StreamsInfo) -> Result<StreamMap, ArchiveError> {
     let num_folders = streams_info.folders.len();
     let mut next_folder_pack_stream_index = 0;
     let mut folder_first_pack_stream_index = Vec::with_capacity(num_folders);
@@ -128,11 +150,15 @@ fn calculate_stream_map(files: &Vec<File>, streams_info: &StreamsInfo) -> Result
     }
 
     let num_pack_sizes = streams_info.pack_info.pack_sizes.len();
-    let mut next_pack_stream_offset: usize = 0;
-    let mut pack_stream_offsets = Vec::with_capacity(num_folders);
+    if next_folder_pack_stream_index > num_pack_sizes {
+        return Err(ArchiveError::with_kind(ErrorKind::HeaderCorrupt, &format!("The folders use {} packed streams, but only {} are listed", next_folder_pack_stream_index, num_pack_sizes)));
+    }
+    let mut next_pack_stream_offset: u64 = 0;
+    let mut pack_stream_offsets = Vec::with_capacity(num_pack_sizes);
     for i in 0..num_pack_sizes {
         pack_stream_offsets.push(next_pack_stream_offset);
-        next_pack_stream_offset += streams_info.pack_info.pack_sizes[i] as usize;
+        next_pack_stream_offset = next_pack_stream_offset.checked_add(streams_info.pack_info.pack_sizes[i])
+            .ok_or_else(|| ArchiveError::with_kind(ErrorKind::HeaderCorrupt, "Pack sizes overflow"))?;
     }
 
     let mut folder_first_file_index = vec![None; files.len()];
@@ -181,21 +207,13 @@ fn calculate_stream_map(files: &Vec<File>, streams_info: &StreamsInfo) -> Result
 fn skip_archive_properties<R>(buf: &mut R) -> Result<(), ArchiveError> where R: io::BufRead {
     let mut nid = read_nid(buf)?;
     while nid != NID::End {
-        let property_size = dyn64(buf);
+        let property_size = dyn64(buf)?;
         skip(buf, property_size as usize)?;
         nid = read_nid(buf)?;
     }
     Ok(())
 }
 
-fn skip_external<R>(buf: &mut R) -> Result<(), ArchiveError> where R: io::BufRead {
-    let external = buf.read_u8().unwrap();
-    if external != 0 {
-        return Err(ArchiveError::new("External unsupported"));
-    }
-    Ok(())
-}
-
 fn utf16_decode(data: &[u8]) -> Result<String, FromUtf16Error> {
     // return String::from_utf8(data.to_vec());
 
@@ -210,35 +228,36 @@ fn utf16_decode(data: &[u8]) -> Result<String, FromUtf16Error> {
 
 }
 
-fn read_dates<R>(buf: &mut R, num_files: u64) -> Result<Vec<Option<u64>>, ArchiveError> where R: io::BufRead {
-    let mut dates: Vec<Option<u64>> = Vec::new();
-    let times_defined = read_utils::read_all_or_bits(buf, num_files as usize);
-    skip_external(buf)?;
-    for i in 0..num_files {
-        dates.push(if times_defined.contains(i as usize) {
-            Some(read_utils::read_uint64(buf))
-        } else {
-            None
-        })
+fn read_dates<R>(buf: &mut R, num_files: u64, additional_streams: &[Vec<u8>]) -> Result<Vec<Option<u64>>, ArchiveError> where R: io::BufRead {
+    let times_defined = read_utils::read_all_or_bits(buf, num_files as usize)?;
+    match read_utils::read_external(buf, additional_streams)? {
+        Some(mut external) => read_defined(&mut external, &times_defined, num_files, read_utils::read_uint64),
+        None => read_defined(buf, &times_defined, num_files, read_utils::read_uint64)
+    }
+}
+
+fn read_win_attributes<R>(buf: &mut R, num_files: u64, additional_streams: &[Vec<u8>]) -> Result<Vec<Option<u32>>, ArchiveError> where R: io::BufRead {
+    let attrs_defined = read_utils::read_all_or_bits(buf, num_files as usize)?;
+    match read_utils::read_external(buf, additional_streams)? {
+        Some(mut external) => read_defined(&mut external, &attrs_defined, num_files, read_utils::read_uint32),
+        None => read_defined(buf, &attrs_defined, num_files, read_utils::read_uint32)
     }
-    Ok(dates)
 }
 
-fn read_win_attributes<R>(buf: &mut R, num_files: u64) -> Result<Vec<Option<u32>>, ArchiveError> where R: io::BufRead {
-    let mut attrs: Vec<Option<u32>> = Vec::new();
-    let times_defined = read_utils::read_all_or_bits(buf, num_files as usize);
-    skip_external(buf)?;
+fn read_defined<R, T>(buf: &mut R, defined: &bit_set::BitSet, num_files: u64, read: fn(&mut R) -> Result<T, ArchiveError>) -> Result<Vec<Option<T>>, ArchiveError> where R: io::BufRead {
+    let mut values: Vec<Option<T>> = Vec::with_capacity(num_files as usize);
     for i in 0..num_files {
-        attrs.push(if times_defined.contains(i as usize) {
-            Some(read_utils::read_uint32(buf))
+        values.push(if defined.contains(i as usize) {
+            Some(read(buf)?)
         } else {
             None
         })
     }
-    Ok(attrs)
+    Ok(values)
 }
 
 #[derive(Debug)]
+#[allow(dead_code)]
 pub struct File {
     name: String,
     has_stream: bool,
@@ -249,11 +268,12 @@ pub struct File {
     access_date: Option<u64>,
     windows_attributes: Option<u32>,
     size: u64,
+    crc: Option<u32>,
     // compressed_size: u64,
 }
 
-fn read_files_info<R>(buf: &mut R, substreams_info: &SubstreamsInfo) -> Result<Vec<File>, ArchiveError> where R: io::BufRead {
-    let num_files = dyn64(buf);
+fn read_files_info<R>(buf: &mut R, substreams_info: &SubstreamsInfo, additional_streams: &[Vec<u8>]) -> Result<Vec<File>, ArchiveError> where R: io::BufRead {
+    let num_files = dyn64(buf)?;
     let mut is_empty_stream = bit_set::BitSet::with_capacity(num_files as usize);
     let mut is_empty_file = None; // bit_set::BitSet::with_capacity(num_files as usize);
     let mut is_anti = bit_set::BitSet::with_capacity(num_files as usize);
@@ -270,24 +290,32 @@ fn read_files_info<R>(buf: &mut R, substreams_info: &SubstreamsInfo) -> Result<V
         if nid == NID::End {
             break;
         }
-        let size = dyn64(buf);
+        let size = dyn64(buf)?;
 
         match nid {
-            NID::EmptyStream => is_empty_stream = read_utils::read_bits(buf, num_files as usize),
+            NID::EmptyStream => is_empty_stream = read_utils::read_bits(buf, num_files as usize)?,
             NID::EmptyFile => {
-                is_empty_file = Some(read_utils::read_bits(buf, is_empty_stream.len()));
+                is_empty_file = Some(read_utils::read_bits(buf, is_empty_stream.len())?);
             },
-            NID::Anti => is_anti = read_utils::read_bits(buf, is_empty_stream.len()),
+            NID::Anti => is_anti = read_utils::read_bits(buf, is_empty_stream.len())?,
             NID::Name => {
-                skip_external(buf)?;
-                if ((size - 1) & 1) != 0 {
+                let names = match read_utils::read_external(buf, additional_streams)? {
+                    Some(external) => external.to_vec(),
+                    None => {
+                        // The size includes the external flag byte that was just read
+                        let length = size.checked_sub(1).ok_or_else(|| ArchiveError::with_kind(ErrorKind::HeaderCorrupt, "File names property is empty"))?;
+                        let mut names = Vec::new();
+                        or_archive_error(buf.by_ref().take(length).read_to_end(&mut names))?;
+                        if (names.len() as u64) < length {
+                            return Err(ArchiveError::with_kind(ErrorKind::HeaderCorrupt, "File names are truncated"));
+                        }
+                        names
+                    }
+                };
+                if (names.len() & 1) != 0 {
                     return Err(ArchiveError::new("File names length invalid"));
                 }
 
-
-                let mut names = vec![0u8; (size - 1) as usize];
-                or_archive_error(buf.read_exact(&mut names))?;
-
                 let mut next_name_pos = 0;
                 for x in 0..(names.len() / 2) {
                     let i = 2 * x;
@@ -300,16 +328,16 @@ fn read_files_info<R>(buf: &mut R, substreams_info: &SubstreamsInfo) -> Result<V
                 }
             }
             NID::Ctime => {
-                file_creation_dates = read_dates(buf, num_files)?;
+                file_creation_dates = read_dates(buf, num_files, additional_streams)?;
             }
             NID::Atime => {
-                file_access_dates = read_dates(buf, num_files)?;
+                file_access_dates = read_dates(buf, num_files, additional_streams)?;
             }
             NID::Mtime => {
-                file_modified_dates = read_dates(buf, num_files)?;
+                file_modified_dates = read_dates(buf, num_files, additional_streams)?;
             }
             NID::WinAttributes => {
-                win_attributes = read_win_attributes(buf, num_files)?;
+                win_attributes = read_win_attributes(buf, num_files, additional_streams)?;
             }
             NID::StartPos => {
                 return Err(ArchiveError::new("StartPos is unsupported, please report"));
@@ -325,12 +353,15 @@ fn read_files_info<R>(buf: &mut R, substreams_info: &SubstreamsInfo) -> Result<V
 
     let mut non_empty_file_counter = 0;
     let mut empty_file_counter = 0;
+    let missing_name = || ArchiveError::with_kind(ErrorKind::HeaderCorrupt, "There are fewer file names than files");
+    let missing_stream = || ArchiveError::with_kind(ErrorKind::HeaderCorrupt, "There are fewer substreams than files with data");
 
     for i in 0..(num_files as usize) {
-        let has_stream = !is_empty_stream.contains(i as usize);
+        let name = file_names.get(i).ok_or_else(missing_name)?.to_string();
+        let has_stream = !is_empty_stream.contains(i);
         if has_stream {
             files.push(File {
-                name: file_names[i].to_string(),
+                name,
                 has_stream: true,
                 is_directory: false,
                 is_anti_item: false,
@@ -338,7 +369,8 @@ fn read_files_info<R>(buf: &mut R, substreams_info: &SubstreamsInfo) -> Result<V
                 last_modified_date: file_modified_dates[non_empty_file_counter],
                 access_date: file_access_dates[non_empty_file_counter],
                 windows_attributes: win_attributes[non_empty_file_counter],
-                size: substreams_info.unpack_sizes[non_empty_file_counter],
+                size: *substreams_info.unpack_sizes.get(non_empty_file_counter).ok_or_else(missing_stream)?,
+                crc: *substreams_info.crcs.get(non_empty_file_counter).ok_or_else(missing_stream)?,
                 // compressed_size: 0, // TODO fix
             });
             non_empty_file_counter += 1;
@@ -349,7 +381,7 @@ fn read_files_info<R>(buf: &mut R, substreams_info: &SubstreamsInfo) -> Result<V
             };
 
             files.push(File {
-                name: file_names[i].to_string(),
+                name,
                 has_stream: false,
                 is_directory,
                 is_anti_item: is_anti.contains(empty_file_counter),
@@ -358,6 +390,7 @@ fn read_files_info<R>(buf: &mut R, substreams_info: &SubstreamsInfo) -> Result<V
                 access_date: None,
                 windows_attributes: None,
                 size: 0,
+                crc: None,
                 // compressed_size: 0,
             });
             empty_file_counter += 1;
@@ -366,3 +399,35 @@ fn read_files_info<R>(buf: &mut R, substreams_info: &SubstreamsInfo) -> Result<V
 
     Ok(files)
 }
+
+mod tests {
+    #[test]
+    fn external_attributes() -> Result<(), super::ArchiveError> {
+        let bytes = include_bytes!("../../tests/additional_streams.7z");
+        let header = super::super::read_archive_header(bytes, &Default::default())?;
+        assert_eq!(header.files_info[0].windows_attributes, Some(0x20));
+        assert_eq!(header.files_info[1].windows_attributes, Some(0x21));
+        Ok(())
+    }
+
+    #[test]
+    fn missing_additional_stream() {
+        let additional_streams = vec![vec![0u8; 4]];
+        let result = super::read_utils::read_external(&mut &[1u8, 3][..], &additional_streams);
+        assert!(result.is_err(), "Should return an error");
+    }
+
+    #[test]
+    fn corrupt_files_info() {
+        let substreams_info = super::SubstreamsInfo { unpack_sizes: vec![3], crcs: vec![None] };
+        // One file, with an empty names property
+        let result = super::read_files_info(&mut &[1u8, 17, 0, 0][..], &substreams_info, &[]);
+        assert_eq!(result.err().unwrap().message, "File names property is empty");
+        // Two files, but only the name "a"
+        let result = super::read_files_info(&mut &[2u8, 17, 5, 0, b'a', 0, 0, 0, 0][..], &substreams_info, &[]);
+        assert_eq!(result.err().unwrap().message, "There are fewer file names than files");
+        // Two files with data, but a single substream
+        let result = super::read_files_info(&mut &[2u8, 17, 9, 0, b'a', 0, 0, 0, b'b', 0, 0, 0, 0][..], &substreams_info, &[]);
+        assert_eq!(result.err().unwrap().message, "There are fewer substreams than files with data");
+    }
+}
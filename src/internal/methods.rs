@@ -0,0 +1,148 @@
+// Method names and properties, described the way 7-Zip lists them (e.g. "LZMA2:24 BCJ")
+use internal::ArchiveError;
+use internal::crypto;
+use internal::decode;
+use super::byteorder::{ByteOrder, LittleEndian};
+
+/// The 7z method IDs, with their names as 7-Zip shows them
+pub const METHODS: &[(&[u8], &str)] = &[
+    (&[0x00], "Copy"),
+    (&[0x03], "Delta"),
+    (&[0x0A], "ARM64"),
+    (&[0x0B], "RISCV"),
+    (&[0x21], "LZMA2"),
+    (&[0x03, 0x01, 0x01], "LZMA"),
+    (&[0x03, 0x03, 0x01, 0x03], "BCJ"),
+    (&[0x03, 0x03, 0x01, 0x1B], "BCJ2"),
+    (&[0x03, 0x03, 0x02, 0x05], "PPC"),
+    (&[0x03, 0x03, 0x04, 0x01], "IA64"),
+    (&[0x03, 0x03, 0x05, 0x01], "ARM"),
+    (&[0x03, 0x03, 0x07, 0x01], "ARMT"),
+    (&[0x03, 0x03, 0x08, 0x05], "SPARC"),
+    (&[0x03, 0x04, 0x01], "PPMD"),
+    (&[0x04, 0x01, 0x08], "Deflate"),
+    (&[0x04, 0x01, 0x09], "Deflate64"),
+    (&[0x04, 0x02, 0x02], "BZip2"),
+    (&[0x04, 0xF7, 0x11, 0x01], "ZSTD"),
+    (&[0x04, 0xF7, 0x11, 0x02], "BROTLI"),
+    (&[0x04, 0xF7, 0x11, 0x04], "LZ4"),
+    (&[0x06, 0xF1, 0x07, 0x01], "7zAES"),
+];
+
+pub fn method_name(method_id: &[u8]) -> Option<&'static str> {
+    METHODS.iter().find(|(id, _)| *id == method_id).map(|(_, name)| *name)
+}
+
+// Powers of two are shown as the exponent, other sizes with a unit
+fn format_size(size: u64) -> String {
+    if size.is_power_of_two() {
+        return size.trailing_zeros().to_string();
+    }
+    if size.is_multiple_of(1 << 20) {
+        return format!("{}m", size >> 20);
+    }
+    if size.is_multiple_of(1 << 10) {
+        return format!("{}k", size >> 10);
+    }
+    format!("{}b", size)
+}
+
+// The lc/lp/pb values are only shown when they differ from the defaults (3, 0 and 2)
+fn describe_lzma(properties: &[u8]) -> Result<String, ArchiveError> {
+    let (props, dict_size) = decode::read_lzma_properties(properties)?;
+    let lc = props % 9;
+    let lp = (props / 9) % 5;
+    let pb = props / 45;
+    let mut description = format_size(dict_size as u64);
+    if lc != 3 {
+        description += &format!(":lc{}", lc);
+    }
+    if lp != 0 {
+        description += &format!(":lp{}", lp);
+    }
+    if pb != 2 {
+        description += &format!(":pb{}", pb);
+    }
+    Ok(description)
+}
+
+fn describe_ppmd(properties: &[u8]) -> Result<String, ArchiveError> {
+    if properties.len() < 5 {
+        return Err(ArchiveError::new("Invalid PPMd properties"));
+    }
+    Ok(format!("o{}:mem{}", properties[0], format_size(LittleEndian::read_u32(&properties[1..5]) as u64)))
+}
+
+fn describe_start_offset(properties: &[u8], alignment: u32) -> Result<String, ArchiveError> {
+    match decode::read_start_offset(properties, alignment)? {
+        0 => Ok(String::new()),
+        start_offset => Ok(format!("0x{:X}", start_offset))
+    }
+}
+
+/// The decoded properties of a coder, like "24" for a 16 MiB LZMA2 dictionary.
+/// Empty for methods without (or with unknown) properties.
+pub fn describe_properties(method_id: &[u8], properties: &[u8]) -> Result<String, ArchiveError> {
+    match method_id {
+        [0x21] => Ok(format_size(decode::read_lzma2_dict_size(properties)?)),
+        [0x03, 0x01, 0x01] => describe_lzma(properties),
+        [0x03, 0x04, 0x01] => describe_ppmd(properties),
+        [0x03] => match properties {
+            [distance] => Ok((*distance as u32 + 1).to_string()),
+            _ => Err(ArchiveError::new("Invalid Delta properties"))
+        },
+        [0x0A] => describe_start_offset(properties, 4),
+        [0x0B] => describe_start_offset(properties, 2),
+        [0x06, 0xF1, 0x07, 0x01] => Ok(crypto::read_cycles_power(properties)?.to_string()),
+        _ => Ok(String::new())
+    }
+}
+
+/// The method name followed by its decoded properties, like "LZMA2:24".
+/// Methods without a name are shown as their ID in hex.
+pub fn describe_coder(method_id: &[u8], properties: &[u8]) -> String {
+    let name = match method_name(method_id) {
+        Some(name) => name.to_string(),
+        None => method_id.iter().map(|b| format!("{:02X}", b)).collect()
+    };
+    match describe_properties(method_id, properties) {
+        Ok(ref description) if !description.is_empty() => format!("{}:{}", name, description),
+        _ => name
+    }
+}
+
+mod tests {
+    #[test]
+    fn lzma2() {
+        assert_eq!(super::describe_coder(&[0x21], &[24]), "LZMA2:24");
+        assert_eq!(super::describe_coder(&[0x21], &[25]), "LZMA2:24m");
+        assert_eq!(super::describe_coder(&[0x21], &[41]), "LZMA2");
+    }
+
+    #[test]
+    fn lzma() {
+        assert_eq!(super::describe_coder(&[0x3, 0x1, 0x1], &[0x5D, 0, 0, 0, 1]), "LZMA:24");
+        assert_eq!(super::describe_coder(&[0x3, 0x1, 0x1], &[(2 * 5 + 2) * 9, 0, 0, 1, 0]), "LZMA:16:lc0:lp2");
+        assert_eq!(super::describe_coder(&[0x3, 0x1, 0x1], &[0x5D, 0, 0x30, 0, 0]), "LZMA:12k");
+    }
+
+    #[test]
+    fn filters() {
+        assert_eq!(super::describe_coder(&[0x3], &[3]), "Delta:4");
+        assert_eq!(super::describe_coder(&[0x3, 0x3, 0x1, 0x3], &[]), "BCJ");
+        assert_eq!(super::describe_coder(&[0xA], &[0, 0x10, 0, 0]), "ARM64:0x1000");
+        assert_eq!(super::describe_coder(&[0x3, 0x4, 0x1], &[6, 0, 0, 0, 1]), "PPMD:o6:mem24");
+    }
+
+    #[test]
+    fn aes() {
+        let mut properties = vec![0x40 | 19, 0x0F];
+        properties.extend_from_slice(&[7u8; 16]);
+        assert_eq!(super::describe_coder(&[0x6, 0xF1, 0x7, 0x1], &properties), "7zAES:19");
+    }
+
+    #[test]
+    fn unknown_method() {
+        assert_eq!(super::describe_coder(&[0x7F, 0x01], &[0x5A]), "7F01");
+    }
+}
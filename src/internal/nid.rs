@@ -2,6 +2,12 @@ use internal::ArchiveError;
 use std::io;
 use super::byteorder::ReadBytesExt;
 
+fn or_archive_error<R>(result: Result<R, io::Error>) -> Result<R, ArchiveError> {
+    result.map_err(|e| ArchiveError::new(&e.to_string()))
+}
+
+// Named like in the 7z format documentation
+#[allow(clippy::upper_case_acronyms)]
 #[derive(Debug, PartialEq)]
 pub enum NID {
     End,
@@ -33,8 +39,8 @@ pub enum NID {
 }
 
 pub fn read_nid<R>(buf: &mut R) -> Result<NID, ArchiveError> where R: io::BufRead {
-    let i = buf.read_u8().unwrap();
-    let res = match i {
+    let i = or_archive_error(buf.read_u8())?;
+    match i {
         0 => Ok(NID::End),
         1 => Ok(NID::Header),
         2 => Ok(NID::ArchiveProperties),
@@ -62,9 +68,7 @@ pub fn read_nid<R>(buf: &mut R) -> Result<NID, ArchiveError> where R: io::BufRea
         24 => Ok(NID::StartPos),
         25 => Ok(NID::Dummy),
         _ => Err(ArchiveError::new(&format!("Unrecognized NID flag {}", i)))
-    };
-    println!("Read NID {:?} ({})", res, i);
-    res
+    }
 }
 
 mod tests {
@@ -1,56 +1,72 @@
+use internal::ArchiveError;
 use super::byteorder::{LittleEndian, ReadBytesExt};
 use std::io;
 
+fn or_archive_error<R>(result: Result<R, io::Error>) -> Result<R, ArchiveError> {
+    result.map_err(|e| ArchiveError::new(&e.to_string()))
+}
 
-pub fn read_uint32<R>(data: &mut R) -> u32 where R: io::BufRead {
-    return data.read_u32::<LittleEndian>().unwrap();
+pub fn read_uint32<R>(data: &mut R) -> Result<u32, ArchiveError> where R: io::BufRead {
+    or_archive_error(data.read_u32::<LittleEndian>())
 }
 
-pub fn read_uint64<R>(data: &mut R) -> u64 where R: io::BufRead {
-    return data.read_u64::<LittleEndian>().unwrap();
+pub fn read_uint64<R>(data: &mut R) -> Result<u64, ArchiveError> where R: io::BufRead {
+    or_archive_error(data.read_u64::<LittleEndian>())
 }
 
 
-pub fn read_dyn_uint64<R>(data: &mut R) -> u64 where R: io::BufRead {
-    let first_byte: u64 = data.read_u8().unwrap() as u64;
+pub fn read_dyn_uint64<R>(data: &mut R) -> Result<u64, ArchiveError> where R: io::BufRead {
+    let first_byte: u64 = or_archive_error(data.read_u8())? as u64;
     let mut mask: u64 = 0x80;
     let mut value: u64 = 0;
     for i in 0..8 {
         if (first_byte & mask) == 0 {
-            return value | ((first_byte & (mask - 1)) << (8 * i));
+            return Ok(value | ((first_byte & (mask - 1)) << (8 * i)));
         }
-        let next_byte: u64 = data.read_u8().unwrap() as u64;
+        let next_byte: u64 = or_archive_error(data.read_u8())? as u64;
         value |= next_byte << (8 * i);
         mask >>= 1;
     }
-    value
+    Ok(value)
+}
+
+// Data flagged as external isn't stored inline, but in one of the additional streams,
+// given by its index. Returns that stream, or None if the data follows inline.
+pub fn read_external<'a, R>(data: &mut R, additional_streams: &'a [Vec<u8>]) -> Result<Option<&'a [u8]>, ArchiveError> where R: io::BufRead {
+    let external = or_archive_error(data.read_u8())?;
+    if external == 0 {
+        return Ok(None);
+    }
+    let index = read_dyn_uint64(data)?;
+    additional_streams.get(index as usize)
+        .map(|stream| Some(stream.as_slice()))
+        .ok_or_else(|| ArchiveError::new(&format!("External data refers to a missing additional stream {}", index)))
 }
 
-pub fn read_all_or_bits<R>(data: &mut R, size: usize) -> bit_set::BitSet where R: io::BufRead {
-    let all_defined = data.read_u8().unwrap() as u8;
+pub fn read_all_or_bits<R>(data: &mut R, size: usize) -> Result<bit_set::BitSet, ArchiveError> where R: io::BufRead {
+    let all_defined = or_archive_error(data.read_u8())?;
     if all_defined != 0 {
-        (0..size).filter(|_| true).collect()
+        Ok((0..size).filter(|_| true).collect())
     } else {
         read_bits(data, size)
     }
 }
 
-pub fn read_bits<R>(data: &mut R, size: usize) -> bit_set::BitSet where R: io::BufRead {
-    println!("Read bits {}", size);
+pub fn read_bits<R>(data: &mut R, size: usize) -> Result<bit_set::BitSet, ArchiveError> where R: io::BufRead {
     let mut set = bit_set::BitSet::with_capacity(size);
     let mut mask = 0;
     let mut cache = 0;
     for i in 0..size {
         if mask == 0 {
             mask = 0x80;
-            cache = data.read_u8().unwrap();
+            cache = or_archive_error(data.read_u8())?;
         }
         if cache & mask != 0 {
             set.insert(i);
         }
         mask >>= 1;
     }
-    set
+    Ok(set)
 }
 
 mod tests_uint32 {
@@ -58,32 +74,39 @@ mod tests_uint32 {
     #[test]
     fn read_uint32_zero() {
         let mut buff = std::io::Cursor::new(vec![0, 0, 0, 0]);
-        let result = super::read_uint32(&mut buff);
+        let result = super::read_uint32(&mut buff).unwrap();
         assert_eq!(result, 0);
     }
 
     #[test]
     fn read_uint32_257() {
         let mut buff = std::io::Cursor::new(vec![1, 1, 0, 0]);
-        let result = super::read_uint32(&mut buff);
+        let result = super::read_uint32(&mut buff).unwrap();
         assert_eq!(result, 257);
     }
 
     #[test]
     fn read_uint32_4096() {
         let mut buff = std::io::Cursor::new(vec![0, 16, 0, 0]);
-        let result = super::read_uint32(&mut buff);
+        let result = super::read_uint32(&mut buff).unwrap();
         assert_eq!(result, 4096);
     }
 
     #[test]
     fn read_two_numbers() {
         let mut buff = std::io::Cursor::new(vec![0, 16, 0, 0, 0, 8, 0, 0]);
-        let result1 = super::read_uint32(&mut buff);
-        let result2 = super::read_uint32(&mut buff);
+        let result1 = super::read_uint32(&mut buff).unwrap();
+        let result2 = super::read_uint32(&mut buff).unwrap();
         assert_eq!(result1, 4096);
         assert_eq!(result2, 2048);
     }
+
+    #[test]
+    fn read_uint32_eof() {
+        let mut buff = std::io::Cursor::new(vec![0, 16]);
+        let result = super::read_uint32(&mut buff);
+        assert!(result.is_err(), "Should return an error");
+    }
 }
 
 mod tests_uint64 {
@@ -91,21 +114,21 @@ mod tests_uint64 {
     #[test]
     fn read_uint64_zero() {
         let mut buff = std::io::Cursor::new(vec![0, 0, 0, 0, 0, 0, 0, 0]);
-        let result = super::read_uint64(&mut buff);
+        let result = super::read_uint64(&mut buff).unwrap();
         assert_eq!(result, 0);
     }
 
     #[test]
     fn read_uint64_257() {
         let mut buff = std::io::Cursor::new(vec![1, 2, 3, 4, 200, 201, 202, 203]);
-        let result = super::read_uint64(&mut buff);
+        let result = super::read_uint64(&mut buff).unwrap();
         assert_eq!(result, 14684771395892871681);
     }
 
     #[test]
     fn read_uint64_4096() {
         let mut buff = std::io::Cursor::new(vec![0, 16, 0, 0, 0, 0, 0, 0]);
-        let result = super::read_uint64(&mut buff);
+        let result = super::read_uint64(&mut buff).unwrap();
         assert_eq!(result, 4096);
     }
 }
@@ -115,14 +138,14 @@ mod tests_dyn_uint64 {
     #[test]
     fn read_real_uint64_zero() {
         let mut buff = std::io::Cursor::new(vec![0]);
-        let result = super::read_dyn_uint64(&mut buff);
+        let result = super::read_dyn_uint64(&mut buff).unwrap();
         assert_eq!(result, 0);
     }
 
     #[test]
     fn read_real_uint64_2199140894112() {
         let mut buff = std::io::Cursor::new(vec![250, 160, 5, 3, 7, 0, 0, 1, 3]);
-        let result = super::read_dyn_uint64(&mut buff);
+        let result = super::read_dyn_uint64(&mut buff).unwrap();
         assert_eq!(result, 2199140894112);
     }
 }
@@ -131,7 +154,7 @@ mod tests_read_all_or_bits {
 
     #[test]
     fn all_bits_true() {
-        let bitset = super::read_all_or_bits(&mut std::io::Cursor::new(vec![1, 0]), 3);
+        let bitset = super::read_all_or_bits(&mut std::io::Cursor::new(vec![1, 0]), 3).unwrap();
         assert!(bitset.contains(0), "All bits should be true");
         assert!(bitset.contains(1), "All bits should be true");
         assert!(bitset.contains(2), "All bits should be true");
@@ -139,12 +162,12 @@ mod tests_read_all_or_bits {
 
     #[test]
     fn read_bits() {
-        let bitset = super::read_all_or_bits(&mut std::io::Cursor::new(vec![0, 128]), 4);
+        let bitset = super::read_all_or_bits(&mut std::io::Cursor::new(vec![0, 128]), 4).unwrap();
         let vec = bitset.into_bit_vec();
-        assert_eq!(vec[0], true);
-        assert_eq!(vec[1], false);
-        assert_eq!(vec[2], false);
-        assert_eq!(vec[3], false);
+        assert!(vec[0]);
+        assert!(!vec[1]);
+        assert!(!vec[2]);
+        assert!(!vec[3]);
         assert_eq!(vec.len(), 4);
     }
 }
@@ -18,6 +18,8 @@ extern {
 pub struct File {
     name: String,
     data: js_sys::Uint8Array,
+    crc_mismatch: bool,
+    method: String,
 }
 
 #[wasm_bindgen]
@@ -31,6 +33,113 @@ impl File {
     pub fn data(&self) -> js_sys::Uint8Array {
         self.data.clone()
     }
+
+    /// Only ever true with the "lenient" verification level
+    #[wasm_bindgen(getter = crcMismatch)]
+    pub fn crc_mismatch(&self) -> bool {
+        self.crc_mismatch
+    }
+
+    /// The coders of the file's folder in decoding order, with their properties (e.g. "LZMA2:24 BCJ")
+    #[wasm_bindgen(getter)]
+    pub fn method(&self) -> String {
+        self.method.clone()
+    }
+}
+
+#[wasm_bindgen]
+#[derive(Default)]
+pub struct DecompressOptions {
+    verification: Option<String>,
+    password: Option<String>,
+    memory_limit: Option<Option<f64>>,
+    #[cfg(target_arch = "wasm32")]
+    decoders: Vec<(Vec<u8>, JsDecoder)>,
+}
+
+// A decoder implemented in JavaScript. Native builds need decoders that can be sent between
+// threads, which JavaScript functions can't, so these only exist in the WebAssembly build.
+#[cfg(target_arch = "wasm32")]
+#[derive(Clone)]
+struct JsDecoder {
+    decode: js_sys::Function,
+    num_in_streams: usize,
+}
+
+#[cfg(target_arch = "wasm32")]
+impl internal::codec::Decoder for JsDecoder {
+    fn num_in_streams(&self) -> usize {
+        self.num_in_streams
+    }
+
+    fn decode(&self, inputs: &[&[u8]], properties: &[u8], unpack_size: u64, _options: &internal::DecompressOptions) -> Result<Vec<u8>, internal::ArchiveError> {
+        let js_inputs: Array = inputs.iter().map(|&input| Uint8Array::from(input)).collect();
+        let result = self.decode.call3(&JsValue::NULL, &js_inputs, &Uint8Array::from(properties), &JsValue::from_f64(unpack_size as f64))
+            .map_err(|e| internal::ArchiveError::new(&format!("Decoder failed: {:?}", e)))?;
+        let output = result.dyn_into::<Uint8Array>()
+            .map_err(|_| internal::ArchiveError::new("Decoder didn't return a Uint8Array"))?;
+        Ok(output.to_vec())
+    }
+}
+
+#[wasm_bindgen]
+impl DecompressOptions {
+    #[wasm_bindgen(constructor)]
+    pub fn new() -> DecompressOptions {
+        DecompressOptions::default()
+    }
+
+    /// One of "strict" (the default), "lenient" or "off"
+    #[wasm_bindgen(setter)]
+    pub fn set_verification(&mut self, verification: String) {
+        self.verification = Some(verification);
+    }
+
+    /// The password for encrypted archives
+    #[wasm_bindgen(setter)]
+    pub fn set_password(&mut self, password: String) {
+        self.password = Some(password);
+    }
+
+    /// The most memory (in bytes) a decoder may allocate for its dictionary, or null for no limit.
+    /// Defaults to 1 GiB.
+    #[wasm_bindgen(setter = memoryLimit)]
+    pub fn set_memory_limit(&mut self, memory_limit: Option<f64>) {
+        self.memory_limit = Some(memory_limit);
+    }
+
+    /// Adds (or replaces) the decoder for a 7z method ID. The function is called with the input
+    /// streams (an array of Uint8Array), the coder properties (a Uint8Array) and the unpack size,
+    /// and returns the decoded Uint8Array.
+    #[cfg(target_arch = "wasm32")]
+    #[wasm_bindgen(js_name = registerDecoder)]
+    pub fn register_decoder(&mut self, method_id: Vec<u8>, decode: js_sys::Function, num_in_streams: Option<usize>) {
+        self.decoders.push((method_id, JsDecoder { decode, num_in_streams: num_in_streams.unwrap_or(1) }));
+    }
+}
+
+impl DecompressOptions {
+    fn to_internal(&self) -> Result<internal::DecompressOptions, JsValue> {
+        let verification = match self.verification.as_deref() {
+            None | Some("strict") => internal::Verification::Strict,
+            Some("lenient") => internal::Verification::Lenient,
+            Some("off") => internal::Verification::Off,
+            Some(other) => return Err(JsValue::from_str(&format!("Unknown verification level {}", other))),
+        };
+        let mut options = internal::DecompressOptions {
+            verification,
+            password: self.password.clone(),
+            ..Default::default()
+        };
+        #[cfg(target_arch = "wasm32")]
+        for (method_id, decoder) in &self.decoders {
+            options.codecs.register(method_id, decoder.clone());
+        }
+        if let Some(memory_limit) = self.memory_limit {
+            options.memory_limit = memory_limit.map(|limit| limit as u64);
+        }
+        Ok(options)
+    }
 }
 
 #[wasm_bindgen]
@@ -48,7 +157,14 @@ impl Archive {
 
 #[wasm_bindgen]
 pub fn decompress(data: &[u8]) -> Result<Archive, JsValue> {
-    let res = internal::decompress(data).or_else(|e| Err(JsValue::from_str(&e.message)))?;
+    decompress_with_options(data, &DecompressOptions::default())
+}
+
+/// The options are borrowed, so the same object can be passed to several calls.
+#[wasm_bindgen(js_name = decompressWithOptions)]
+pub fn decompress_with_options(data: &[u8], options: &DecompressOptions) -> Result<Archive, JsValue> {
+    let options = options.to_internal()?;
+    let res = internal::decompress_with_options(data, &options).map_err(|e| JsValue::from_str(&e.message))?;
 
     let files = Array::new();
     for file in res.files {
@@ -56,7 +172,9 @@ pub fn decompress(data: &[u8]) -> Result<Archive, JsValue> {
         let data = Uint8Array::from(buf);
         let f = File {
             name: file.name,
-            data
+            data,
+            crc_mismatch: file.crc_mismatch,
+            method: file.method,
         };
 
         files.push(&JsValue::from(f));
@@ -66,3 +184,61 @@ pub fn decompress(data: &[u8]) -> Result<Archive, JsValue> {
         files,
     })
 }
+
+#[wasm_bindgen]
+pub struct TestEntry {
+    name: String,
+    status: String,
+    error: Option<String>,
+}
+
+#[wasm_bindgen]
+impl TestEntry {
+    #[wasm_bindgen(getter)]
+    pub fn name(&self) -> String {
+        self.name.clone()
+    }
+
+    /// One of "ok", "crc_mismatch" or "decode_error"
+    #[wasm_bindgen(getter)]
+    pub fn status(&self) -> String {
+        self.status.clone()
+    }
+
+    #[wasm_bindgen(getter)]
+    pub fn error(&self) -> Option<String> {
+        self.error.clone()
+    }
+}
+
+/// Decodes the archive and checks all the CRCs, without returning any file data.
+/// Not a streaming check: each folder is decoded into memory in full, so testing a solid archive
+/// needs about as much memory as its unpacked size.
+#[wasm_bindgen(js_name = test)]
+pub fn test_archive(data: &[u8]) -> Result<js_sys::Array, JsValue> {
+    test_archive_with_options(data, &DecompressOptions::default())
+}
+
+#[wasm_bindgen(js_name = testWithOptions)]
+pub fn test_archive_with_options(data: &[u8], options: &DecompressOptions) -> Result<js_sys::Array, JsValue> {
+    let options = options.to_internal()?;
+    let report = internal::test_archive(data, &options).map_err(|e| JsValue::from_str(&e.message))?;
+
+    let entries = Array::new();
+    for entry in report.entries {
+        let (status, error) = match entry.status {
+            internal::TestStatus::Ok => ("ok", None),
+            internal::TestStatus::CrcMismatch => ("crc_mismatch", None),
+            internal::TestStatus::DecodeError(message) => ("decode_error", Some(message)),
+        };
+        let e = TestEntry {
+            name: entry.name,
+            status: String::from(status),
+            error
+        };
+
+        entries.push(&JsValue::from(e));
+    }
+
+    Ok(entries)
+}
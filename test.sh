@@ -1,4 +1,6 @@
 #!/bin/bash
 cargo test
+# The JavaScript decoders only exist in the wasm32 build, so it is checked on its own
+cargo clippy --target wasm32-unknown-unknown --all-targets --all-features -- -D warnings
 wasm-pack test --node
 # wasm-pack test --chrome
@@ -16,3 +16,41 @@ fn pass_tests() {
 
     assert_eq!(files.length(), 2);
 }
+
+#[wasm_bindgen_test]
+fn test_archive() {
+    use js_sys;
+
+    let bytes = include_bytes!("foobar.7z");
+
+    let entries: js_sys::Array = shoeset::test_archive(bytes).expect("Should be success");
+
+    assert_eq!(entries.length(), 2);
+}
+
+// JavaScript decoders only exist in the WebAssembly build
+#[cfg(target_arch = "wasm32")]
+#[wasm_bindgen_test]
+fn javascript_decoder() {
+    use js_sys;
+    use wasm_bindgen::JsCast;
+
+    // Method 7F 01 is made up: every byte is XORed with the property byte
+    let bytes = include_bytes!("custom_method.7z");
+    let decode = js_sys::Function::new_with_args("inputs, properties, unpackSize", "
+        const output = new Uint8Array(unpackSize);
+        for (let i = 0; i < unpackSize; i++) {
+            output[i] = inputs[0][i] ^ properties[0];
+        }
+        return output;
+    ");
+    let mut options = shoeset::DecompressOptions::new();
+    options.register_decoder(vec![0x7F, 0x01], decode, None);
+
+    // The options are borrowed, so they can be used again
+    for _ in 0..2 {
+        let result = shoeset::decompress_with_options(bytes, &options).expect("Should be success");
+        let data = js_sys::Reflect::get(&result.files().get(0), &"data".into()).expect("Should have data");
+        assert_eq!(data.unchecked_into::<js_sys::Uint8Array>().to_vec(), b"Only readable with the custom decoder\n");
+    }
+}